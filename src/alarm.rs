@@ -0,0 +1,207 @@
+//! Wake-up alarms that start playing the weights of a slot at a time of day, slowly fading the noise in.
+//!
+//! Alarms are stored in the config of the daemon, so they survive restarts. Their time is stored as an absolute
+//! point in time instead of a time of day, so that the daemon can tell whether it missed an alarm while it was not
+//! running. Times of day are converted with the local time zone of the system.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    /// Identifies the alarm to cancel it. Assigned by the daemon, so it is ignored when setting an alarm.
+    pub id: u32,
+    /// When the alarm rings next, in seconds since the Unix epoch.
+    pub at: u64,
+    /// Ring at the same time of day every day instead of only once.
+    pub daily: bool,
+    /// The slot of the GUI whose weights are played.
+    pub slot: usize,
+    /// How many seconds the noise takes to fade in from silence.
+    pub ramp_secs: f32,
+}
+
+impl Alarm {
+    /// An alarm that rings at the next time the local clock shows `hour:minute`.
+    pub fn at_time_of_day(
+        hour: u32,
+        minute: u32,
+        daily: bool,
+        slot: usize,
+        ramp_secs: f32,
+    ) -> Result<Self, anyhow::Error> {
+        if hour >= 24 || minute >= 60 {
+            return Err(anyhow!("Invalid time of day {:02}:{:02}", hour, minute));
+        }
+        Ok(Self {
+            id: 0,
+            at: next_time_of_day(hour, minute, unix_secs(SystemTime::now()))?,
+            daily,
+            slot,
+            ramp_secs,
+        })
+    }
+
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.at)
+    }
+
+    pub fn ramp(&self) -> Duration {
+        Duration::try_from_secs_f32(self.ramp_secs).unwrap_or_default()
+    }
+
+    /// When the alarm rings after this time, None if it only rings once.
+    pub fn next(&self) -> Option<u64> {
+        if !self.daily {
+            return None;
+        }
+        let tm = local_time(self.at);
+        next_time_of_day(tm.tm_hour as u32, tm.tm_min as u32, self.at).ok()
+    }
+
+    /// The local date and time of the alarm, e.g. `2024-03-01 07:30`.
+    pub fn local_time_string(&self) -> String {
+        let tm = local_time(self.at);
+        format!(
+            "{}-{:02}-{:02} {:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min
+        )
+    }
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn local_time(secs: u64) -> libc::tm {
+    let secs = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    tm
+}
+
+/// The first time after `after` at which the local clock shows `hour:minute`.
+/// `mktime` takes care of the ends of months and daylight saving time.
+fn next_time_of_day(hour: u32, minute: u32, after: u64) -> Result<u64, anyhow::Error> {
+    let mut tm = local_time(after);
+    for _ in 0..3 {
+        tm.tm_hour = hour as libc::c_int;
+        tm.tm_min = minute as libc::c_int;
+        tm.tm_sec = 0;
+        // Let mktime figure out whether daylight saving time applies on that day.
+        tm.tm_isdst = -1;
+        let time = unsafe { libc::mktime(&mut tm) };
+        if time < 0 {
+            return Err(anyhow!(
+                "Could not convert {:02}:{:02} to a point in time",
+                hour,
+                minute
+            ));
+        }
+        if time as u64 > after {
+            return Ok(time as u64);
+        }
+        // Already over today, so try the next day. mktime normalized the fields, so this also works on the last day
+        // of a month.
+        tm.tm_mday += 1;
+    }
+    Err(anyhow!("Could not find the next time at {:02}:{:02}", hour, minute))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The time zone is process-wide state, so the tests that change it take turns.
+    static TZ: Mutex<()> = Mutex::new(());
+
+    const HOUR: u64 = 60 * 60;
+    const DAY: u64 = 24 * HOUR;
+    /// 2024-03-01 06:00 UTC.
+    const MARCH_1_6AM: u64 = 1_709_272_800;
+    /// Central European time, which switches to summer time on 2024-03-31 and back on 2024-10-27. POSIX rules
+    /// instead of a name, so that the test does not need the tz database.
+    const CET: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    fn with_tz(tz: &str, test: impl FnOnce()) {
+        let _guard = TZ.lock().unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("TZ", tz);
+        extern "C" {
+            fn tzset();
+        }
+        unsafe { tzset() };
+        test();
+    }
+
+    fn daily(at: u64) -> Alarm {
+        Alarm {
+            id: 1,
+            at,
+            daily: true,
+            slot: 0,
+            ramp_secs: 0.0,
+        }
+    }
+
+    #[test]
+    fn later_today_or_tomorrow() {
+        with_tz("UTC0", || {
+            assert_eq!(
+                next_time_of_day(7, 30, MARCH_1_6AM).unwrap(),
+                MARCH_1_6AM + HOUR + 30 * 60
+            );
+            assert_eq!(next_time_of_day(5, 0, MARCH_1_6AM).unwrap(), MARCH_1_6AM + 23 * HOUR);
+            // Right now is already over.
+            assert_eq!(next_time_of_day(6, 0, MARCH_1_6AM).unwrap(), MARCH_1_6AM + DAY);
+            // From the last day of February in a leap year to March.
+            assert_eq!(next_time_of_day(6, 0, MARCH_1_6AM - 2 * HOUR).unwrap(), MARCH_1_6AM);
+            assert_eq!(next_time_of_day(6, 0, MARCH_1_6AM - DAY).unwrap(), MARCH_1_6AM);
+        });
+    }
+
+    #[test]
+    fn daily_alarms_repeat() {
+        with_tz("UTC0", || {
+            let alarm = daily(MARCH_1_6AM);
+            assert_eq!(alarm.next(), Some(MARCH_1_6AM + DAY));
+            assert_eq!(alarm.local_time_string(), "2024-03-01 06:00");
+            let once = Alarm { daily: false, ..alarm };
+            assert_eq!(once.next(), None);
+        });
+    }
+
+    #[test]
+    fn daily_alarms_keep_their_time_across_daylight_saving() {
+        with_tz(CET, || {
+            // 07:00 CET on 2024-03-30, the night before the clocks go forward.
+            let spring = daily(1_711_778_400);
+            assert_eq!(spring.local_time_string(), "2024-03-30 07:00");
+            let next = spring.next().unwrap();
+            assert_eq!(next, spring.at + 23 * HOUR);
+            assert_eq!(daily(next).local_time_string(), "2024-03-31 07:00");
+
+            // 07:00 CEST on 2024-10-26, the night before the clocks go back.
+            let autumn = daily(1_729_918_800);
+            assert_eq!(autumn.local_time_string(), "2024-10-26 07:00");
+            let next = autumn.next().unwrap();
+            assert_eq!(next, autumn.at + 25 * HOUR);
+            assert_eq!(daily(next).local_time_string(), "2024-10-27 07:00");
+        });
+    }
+
+    #[test]
+    fn invalid_times_of_day_are_refused() {
+        let _guard = TZ.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(Alarm::at_time_of_day(24, 0, false, 0, 0.0).is_err());
+        assert!(Alarm::at_time_of_day(7, 60, false, 0, 0.0).is_err());
+        let alarm = Alarm::at_time_of_day(7, 0, false, 0, 0.0).unwrap();
+        assert!(alarm.time() > SystemTime::now());
+        assert!(alarm.time() <= SystemTime::now() + Duration::from_secs(DAY + HOUR));
+    }
+}
@@ -15,77 +15,385 @@
 use anyhow::anyhow;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{FromSample, SizedSample};
+use serde::{Deserialize, Serialize};
 use std::f32;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::samples::BlendingSamples;
+use crate::channels::ChannelSamples;
+use crate::dither::{Dither, DitherMode};
+use crate::gain::{GainControl, GainStage};
+use crate::samples::SAMPLE_RATE;
+use crate::sink::{
+    fit_samples, AudioSink, AudioStream, BufferRequest, ErrorHandler, MakeSamples, SharedSamples, StreamCounters,
+    StreamInfo,
+};
 
-pub struct AudioStream {
-    pub stream: cpal::Stream,
+/// Identifies an output device by the name of its host (e.g. ALSA) and its own name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputDevice {
+    pub host: String,
+    pub name: String,
 }
 
-pub fn play(samples: BlendingSamples) -> Result<AudioStream, anyhow::Error> {
-    let host = cpal::default_host();
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputDeviceInfo {
+    pub device: OutputDevice,
+    /// Whether this is the default output device of its host.
+    pub is_default: bool,
+}
+
+/// Names of the audio hosts that are available on this system.
+pub fn list_hosts() -> Vec<String> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| id.name().to_owned())
+        .collect()
+}
+
+/// All output devices of all available hosts.
+/// Hosts or devices that fail to be queried are skipped.
+pub fn list_output_devices() -> Vec<OutputDeviceInfo> {
+    let mut result = Vec::new();
+
+    for host_id in cpal::available_hosts() {
+        let Ok(host) = cpal::host_from_id(host_id) else {
+            continue;
+        };
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        let Ok(devices) = host.output_devices() else {
+            continue;
+        };
+
+        for device in devices {
+            let Ok(name) = device.name() else {
+                continue;
+            };
+            let is_default = default_name.as_ref() == Some(&name);
+            result.push(OutputDeviceInfo {
+                device: OutputDevice {
+                    host: host_id.name().to_owned(),
+                    name,
+                },
+                is_default,
+            });
+        }
+    }
 
-    let device = host
+    result
+}
+
+/// Look up the chosen output device.
+/// If there is no choice or the chosen device disappeared, we fall back on the default output device.
+fn find_device(choice: Option<&OutputDevice>) -> Result<cpal::Device, anyhow::Error> {
+    if let Some(choice) = choice {
+        let device = cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name() == choice.host)
+            .and_then(|id| cpal::host_from_id(id).ok())
+            .and_then(|host| host.output_devices().ok())
+            .and_then(|mut devices| devices.find(|d| d.name().ok().as_ref() == Some(&choice.name)));
+
+        match device {
+            Some(device) => return Ok(device),
+            None => eprintln!(
+                "Output device {} ({}) not found. Falling back on default device.",
+                choice.name, choice.host
+            ),
+        }
+    }
+
+    cpal::default_host()
         .default_output_device()
-        .ok_or(anyhow!("Failed to find a default output device"))?;
-    let config = device.default_output_config()?;
+        .ok_or(anyhow!("Failed to find a default output device"))
+}
+
+/// All stream configs we try, in order of preference.
+/// First the default config of the device, then all supported configs, preferring the same channel count
+/// as the default config and float formats. The sample rate is kept as close to the default as possible.
+/// Devices without a default config can still play with one of their supported configs.
+fn candidate_configs(device: &cpal::Device) -> Result<Vec<cpal::SupportedStreamConfig>, anyhow::Error> {
+    let default_config = device
+        .default_output_config()
+        .inspect_err(|e| eprintln!("Failed to get the default config of the output device: {}", e))
+        .ok();
+    let supported: Vec<_> = match device.supported_output_configs() {
+        Ok(supported) => supported.collect(),
+        Err(e) if default_config.is_some() => {
+            eprintln!("Failed to get the supported configs of the output device: {}", e);
+            Vec::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let default_rate = default_config
+        .as_ref()
+        .map_or(cpal::SampleRate(SAMPLE_RATE), |config| config.sample_rate());
+    let default_channels = default_config.as_ref().map(|config| config.channels());
+
+    let mut others: Vec<_> = supported
+        .into_iter()
+        .map(|range| {
+            let rate = default_rate.clamp(range.min_sample_rate(), range.max_sample_rate());
+            range.with_sample_rate(rate)
+        })
+        .filter(|config| Some(config) != default_config.as_ref())
+        .collect();
+    others.sort_by_key(|config| {
+        (
+            Some(config.channels()) != default_channels,
+            !config.sample_format().is_float(),
+            config.sample_rate() != default_rate,
+        )
+    });
+    others.dedup();
+
+    let mut configs: Vec<_> = default_config.into_iter().collect();
+    configs.extend(others);
+    if configs.is_empty() {
+        return Err(anyhow!("The output device supports no stream configs"));
+    }
+    Ok(configs)
+}
+
+/// Plays on a sound card via cpal.
+pub struct CpalSink {
+    buffer: BufferRequest,
+    dither: DitherMode,
+}
+
+impl CpalSink {
+    pub fn new(buffer: BufferRequest, dither: DitherMode) -> Self {
+        Self { buffer, dither }
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn channels(&self, device: Option<&OutputDevice>) -> Result<usize, anyhow::Error> {
+        let device = find_device(device)?;
+        Ok(candidate_configs(&device)?[0].channels() as usize)
+    }
+
+    /// Play noise on the chosen output device, or the default one if `device` is None.
+    /// If building a stream with the default config fails, we try all other supported configs before giving up.
+    fn play_samples(
+        &self,
+        device: Option<&OutputDevice>,
+        samples: SharedSamples,
+        make_samples: MakeSamples,
+        gain: GainControl,
+        on_error: ErrorHandler,
+    ) -> Result<AudioStream, anyhow::Error> {
+        let device = find_device(device)?;
+        let configs = candidate_configs(&device)?;
+        if let Ok(name) = device.name() {
+            println!("Using output device {}.", name);
+        }
+
+        // The samples are shared with the stream callback. If building a stream fails, the callback is dropped
+        // and we can reuse the samples for the next attempt, unless that has another number of channels.
+        let mut errors = Vec::new();
+        for config in configs {
+            fit_samples(&samples, config.channels() as usize, make_samples)?;
+            let buffer_frames = self
+                .buffer
+                .frames(config.sample_rate().0)
+                .map(|frames| match config.buffer_size() {
+                    cpal::SupportedBufferSize::Range { min, max } => frames.clamp(*min, *max),
+                    cpal::SupportedBufferSize::Unknown => frames,
+                });
+
+            // Some backends refuse a fixed buffer size, so we try again with the default before moving on.
+            let mut attempts = vec![buffer_frames];
+            if buffer_frames.is_some() {
+                attempts.push(None);
+            }
+            let mut result = Err(anyhow!("No attempt was made"));
+            for buffer_frames in attempts {
+                result = run_with_format(
+                    &device,
+                    &config,
+                    buffer_frames,
+                    self.dither,
+                    samples.clone(),
+                    gain.clone(),
+                    on_error.clone(),
+                );
+                match &result {
+                    Err(e) if buffer_frames.is_some() => {
+                        eprintln!("Failed to use a buffer of {} frames: {}", buffer_frames.unwrap(), e)
+                    }
+                    _ => break,
+                }
+            }
+
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to play with {} on {} channels at {} Hz: {}",
+                        config.sample_format(),
+                        config.channels(),
+                        config.sample_rate().0,
+                        e
+                    );
+                    errors.push(e.to_string());
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "No supported stream config could be used: {}",
+            errors.join("; ")
+        ))
+    }
+}
+
+fn run_with_format(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    buffer_frames: Option<u32>,
+    dither: DitherMode,
+    samples: SharedSamples,
+    gain: GainControl,
+    on_error: ErrorHandler,
+) -> Result<AudioStream, anyhow::Error> {
+    let mut stream_config = config.config();
+    stream_config.buffer_size = match buffer_frames {
+        Some(frames) => cpal::BufferSize::Fixed(frames),
+        None => cpal::BufferSize::Default,
+    };
 
     match config.sample_format() {
-        cpal::SampleFormat::F32 => run::<f32>(&device, &config.into(), samples),
-        cpal::SampleFormat::I16 => run::<i16>(&device, &config.into(), samples),
-        cpal::SampleFormat::U16 => run::<u16>(&device, &config.into(), samples),
-        _ => panic!("Unsupported format"),
+        cpal::SampleFormat::I8 => run::<i8>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::I16 => run::<i16>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::I24 => run::<cpal::I24>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::I32 => run::<i32>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::I64 => run::<i64>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::U8 => run::<u8>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::U16 => run::<u16>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::U32 => run::<u32>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::U64 => run::<u64>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::F32 => run::<f32>(device, &stream_config, dither, samples, gain, on_error),
+        cpal::SampleFormat::F64 => run::<f64>(device, &stream_config, dither, samples, gain, on_error),
+        format => Err(anyhow!("Unsupported sample format {}", format)),
     }
 }
 
 fn run<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
-    samples: BlendingSamples,
+    dither: DitherMode,
+    samples: SharedSamples,
+    gain: GainControl,
+    on_error: ErrorHandler,
 ) -> Result<AudioStream, anyhow::Error>
 where
     T: SizedSample + FromSample<f32>,
 {
     let sample_rate = config.sample_rate.0;
     let channels = config.channels as usize;
+    let buffer_frames = match config.buffer_size {
+        cpal::BufferSize::Fixed(frames) => Some(frames),
+        cpal::BufferSize::Default => None,
+    };
     println!("Playing with sample rate {} on {} channels.", sample_rate, channels);
 
-    let mut samples_iter = samples.into_iter()?;
-    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+    // Buffer for one frame so that we don't allocate in the audio callback.
+    let mut frame_buf = vec![0.0; channels];
+    let mut gain = GainStage::new(gain, sample_rate);
+    let mut dither = Dither::new(dither, T::FORMAT, channels);
+    if dither.is_active() {
+        println!("Dithering to {}.", T::FORMAT);
+    }
+    let err_fn = move |err: cpal::StreamError| {
+        eprintln!("an error occurred on stream: {}", err);
+        on_error(err.to_string())
+    };
 
-    // At this point we give the samples_iter to another thread which actually plays the audio, so it needs to be Send.
+    // At this point we give the samples to another thread which actually plays the audio, so they need to be Send.
+    let callback_samples = samples.clone();
+    let counters = Arc::new(StreamCounters::default());
+    let callback_counters = counters.clone();
+    // When the previous buffer is played and how long it is.
+    let mut previous: Option<(cpal::StreamInstant, Duration)> = None;
     let stream = device.build_output_stream(
         config,
-        move |output: &mut [T], _: &cpal::OutputCallbackInfo| write_data(output, channels, &mut samples_iter),
+        move |output: &mut [T], info: &cpal::OutputCallbackInfo| {
+            // The lock is only contended for the short moment when the daemon swaps in new samples.
+            let mut samples = callback_samples.lock().unwrap();
+            if !write_data(output, &mut frame_buf, &mut samples, &mut gain, &mut dither) {
+                callback_counters.record_underrun();
+            }
+
+            // cpal does not report underruns, so we detect them by a gap between the end of the
+            // previous buffer and the start of this one that is larger than half a buffer.
+            let frames = output.len() / channels;
+            let duration = Duration::from_secs_f64(frames as f64 / sample_rate as f64);
+            let timestamp = info.timestamp();
+            if let Some((previous_playback, previous_duration)) = previous {
+                let gap = timestamp
+                    .playback
+                    .duration_since(&previous_playback)
+                    .and_then(|elapsed| elapsed.checked_sub(previous_duration));
+                if gap.is_some_and(|gap| gap > previous_duration / 2) {
+                    callback_counters.record_underrun();
+                }
+            }
+            previous = Some((timestamp.playback, duration));
+
+            let latency = timestamp
+                .playback
+                .duration_since(&timestamp.callback)
+                .unwrap_or_default();
+            callback_counters.record_callback(frames, latency);
+        },
         err_fn,
         None,
     )?;
     stream.play()?;
 
-    Ok(AudioStream { stream })
+    let info = StreamInfo {
+        description: device.name().unwrap_or_default(),
+        sample_rate,
+        channels,
+        buffer_frames,
+    };
+    Ok(AudioStream::new(samples, info, counters, stream))
 }
 
-fn write_data<'a, T>(output: &mut [T], channels: usize, samples_iter: &mut impl Iterator<Item = (f32, f32)>)
+/// Fill `output` with frames from `samples`, using `frame_buf` as scratch space for one frame.
+/// The frames are dithered before they are converted to an integer format.
+/// Returns false if the samples ran out for some frames, which are then filled with silence.
+pub(crate) fn write_data<T>(
+    output: &mut [T],
+    frame_buf: &mut [f32],
+    samples: &mut ChannelSamples,
+    gain: &mut GainStage,
+    dither: &mut Dither,
+) -> bool
 where
     T: SizedSample + FromSample<f32>,
 {
+    // While paused we don't advance the samples so that playback continues where it stopped.
+    if gain.is_silent() {
+        output.fill(T::EQUILIBRIUM);
+        return true;
+    }
+
+    let mut complete = true;
     // For each sample time we get a frame containing one element per channel.
-    // a.d. TODO How many channels are there? Is it liek stereo -> 2 channels, dolby digital 5.1 -> 5 channels etc.?
-    for frame in output.chunks_mut(channels) {
-        if let Some(sample) = samples_iter.next() {
-            let left = T::from_sample(sample.0);
-            let right: T = T::from_sample(sample.1);
-
-            for (channel, sample) in frame.iter_mut().enumerate() {
-                if channel & 1 == 0 {
-                    *sample = left;
-                } else {
-                    *sample = right;
-                }
-            }
-        } else {
-            println!("Received no sample");
+    for frame in output.chunks_mut(frame_buf.len()) {
+        if !samples.next_frame(frame_buf) {
+            frame_buf.fill(0.0);
+            complete = false;
+        }
+        gain.apply(frame_buf, frame_buf.len());
+        dither.apply(frame_buf);
+
+        for (sample, value) in frame.iter_mut().zip(frame_buf.iter()) {
+            *sample = T::from_sample(*value);
         }
     }
+
+    complete
 }
@@ -0,0 +1,185 @@
+//! Command line client to control the daemon from scripts and keybindings.
+//!
+//! Every command waits for the reply of the daemon, so the exit code tells whether it worked.
+//! It talks to the stream socket, so replies like the status are not limited in size.
+
+use anyhow::anyhow;
+use std::{fs, process::ExitCode};
+use xdg::BaseDirectories;
+
+use adh_rs::{
+    alarm::Alarm,
+    config::MidiTarget,
+    presets::{preset, PRESET_NAMES},
+    protocol::{GUICommand, Reply, StreamProtocol},
+    slots::{Slots, SLOTS_NUM},
+    Weights, WEIGHTS_NUM,
+};
+
+const USAGE: &str = "Usage: adh-ctl [--dev] <command>
+
+Commands:
+  weights preset <name>   play a preset (brown, pink, white, blue, violet)
+  weights slot <0-9>      play weights saved in a slot of the GUI
+  weights file <path>     play weights from a JSON file, either [w0, ..., w31] or {\"v\": [w0, ..., w31]}
+  toggle                  pause or resume playback
+  pause
+  resume
+  volume <0..1>           set the master volume
+  sleep <minutes> [<fade minutes>]
+                          pause after some time, fading out slowly at the end (default fade 5 minutes)
+  sleep off               cancel the sleep timer
+  alarm <HH:MM> <slot> [<ramp minutes>]
+                          play a slot at the next HH:MM, fading in over some minutes (default 10)
+  alarm daily <HH:MM> <slot> [<ramp minutes>]
+                          the same every day
+  alarm list              print the alarms with their ids
+  alarm cancel <id>
+  status                  print the state of the daemon as JSON
+  devices                 print the output devices the daemon can play on
+  hosts                   print the audio hosts of the daemon, e.g. ALSA
+  midi learn band <0-31>  map the next MIDI controller that is moved to a band
+  midi learn volume       map the next MIDI controller that is moved to the volume
+  quit                    fade out and stop the daemon";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    // `--dev` is handled by `is_development`.
+    let args: Vec<String> = std::env::args().skip(1).filter(|arg| arg != "--dev").collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let command = match args.as_slice() {
+        ["weights", "preset", name] => GUICommand::SetWeights(preset(name).ok_or(anyhow!(
+            "Unknown preset {}. Known presets: {}",
+            name,
+            PRESET_NAMES.join(", ")
+        ))?),
+        ["weights", "slot", idx] => {
+            let idx: usize = idx
+                .parse()
+                .ok()
+                .filter(|idx| *idx < SLOTS_NUM)
+                .ok_or(anyhow!("Invalid slot {}", idx))?;
+            let slots = Slots::load_from_disk(&BaseDirectories::with_prefix("adh-rs"));
+            GUICommand::SetWeights(slots.recall_slot(idx))
+        }
+        ["weights", "file", path] => GUICommand::SetWeights(read_weights(path)?),
+        ["toggle"] => GUICommand::Toggle,
+        ["pause"] => GUICommand::Pause,
+        ["resume"] => GUICommand::Resume,
+        ["volume", volume] => GUICommand::SetVolume(volume.parse().map_err(|_| anyhow!("Invalid volume {}", volume))?),
+        ["sleep", "off"] => GUICommand::CancelSleepTimer,
+        ["sleep", minutes] => GUICommand::SetSleepTimer(parse_minutes(minutes)? * 60.0, 300.0),
+        ["sleep", minutes, fade] => {
+            GUICommand::SetSleepTimer(parse_minutes(minutes)? * 60.0, parse_minutes(fade)? * 60.0)
+        }
+        ["alarm", "list"] => GUICommand::ListAlarms,
+        ["alarm", "cancel", id] => GUICommand::CancelAlarm(id.parse().map_err(|_| anyhow!("Invalid alarm id {}", id))?),
+        ["alarm", "daily", alarm @ ..] => GUICommand::SetAlarm(parse_alarm(alarm, true)?),
+        ["alarm", alarm @ ..] => GUICommand::SetAlarm(parse_alarm(alarm, false)?),
+        ["status"] => GUICommand::GetStatus,
+        ["devices"] => GUICommand::ListOutputDevices,
+        ["hosts"] => GUICommand::ListHosts,
+        ["midi", "learn", "band", band] => {
+            let band: usize = band
+                .parse()
+                .ok()
+                .filter(|band| *band < WEIGHTS_NUM)
+                .ok_or(anyhow!("Invalid band {}", band))?;
+            GUICommand::MidiLearn(MidiTarget::Band(band))
+        }
+        ["midi", "learn", "volume"] => GUICommand::MidiLearn(MidiTarget::Volume),
+        ["quit"] => GUICommand::Quit,
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    let protocol = StreamProtocol::connect().map_err(|e| anyhow!("Could not reach the daemon: {}", e))?;
+    protocol.handshake()?;
+    match protocol.request(&command)? {
+        Reply::Ok => Ok(()),
+        Reply::Status(status) => {
+            println!("{}", serde_json::to_string_pretty(&status)?);
+            Ok(())
+        }
+        Reply::Alarms(alarms) => {
+            if alarms.is_empty() {
+                println!("No alarms are set.");
+            }
+            for alarm in alarms {
+                println!(
+                    "{}: {}{}, slot {}, fading in over {:.1} minutes",
+                    alarm.id,
+                    alarm.local_time_string(),
+                    if alarm.daily { " and daily" } else { "" },
+                    alarm.slot,
+                    alarm.ramp_secs / 60.0
+                );
+            }
+            Ok(())
+        }
+        Reply::OutputDevices(devices) => {
+            if devices.is_empty() {
+                println!("No output devices were found.");
+            }
+            for info in devices {
+                println!(
+                    "{}: {}{}",
+                    info.device.host,
+                    info.device.name,
+                    if info.is_default { " (default)" } else { "" }
+                );
+            }
+            Ok(())
+        }
+        Reply::Hosts(hosts) => {
+            for host in hosts {
+                println!("{}", host);
+            }
+            Ok(())
+        }
+        Reply::Error(e) => Err(anyhow!(e)),
+        reply => Err(anyhow!("Unexpected reply from the daemon: {:?}", reply)),
+    }
+}
+
+/// Parse `<HH:MM> <slot> [<ramp minutes>]`.
+fn parse_alarm(args: &[&str], daily: bool) -> Result<Alarm, anyhow::Error> {
+    let (time, slot, ramp) = match args {
+        [time, slot] => (time, slot, 10.0),
+        [time, slot, ramp] => (time, slot, parse_minutes(ramp)?),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let (hour, minute) = time
+        .split_once(':')
+        .and_then(|(hour, minute)| Some((hour.parse().ok()?, minute.parse().ok()?)))
+        .ok_or(anyhow!("Invalid time {}, expected HH:MM", time))?;
+    let slot = slot.parse().map_err(|_| anyhow!("Invalid slot {}", slot))?;
+    Alarm::at_time_of_day(hour, minute, daily, slot, ramp * 60.0)
+}
+
+fn parse_minutes(minutes: &str) -> Result<f32, anyhow::Error> {
+    minutes
+        .parse()
+        .ok()
+        .filter(|minutes: &f32| minutes.is_finite() && *minutes >= 0.0)
+        .ok_or(anyhow!("Invalid number of minutes {}", minutes))
+}
+
+fn read_weights(path: &str) -> Result<Weights, anyhow::Error> {
+    let buf = fs::read(path)?;
+    if let Ok(weights) = serde_json::from_slice::<Weights>(&buf) {
+        return Ok(weights);
+    }
+    let v: [f32; WEIGHTS_NUM] = serde_json::from_slice(&buf)
+        .map_err(|e| anyhow!("{} does not contain {} weights: {}", path, WEIGHTS_NUM, e))?;
+    Ok(Weights { v })
+}
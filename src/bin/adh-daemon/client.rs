@@ -0,0 +1,105 @@
+//! The clients that send commands to the daemon and how replies get back to them.
+
+use anyhow::anyhow;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4, ToSocketAddrs};
+use std::os::{
+    fd::AsRawFd,
+    linux::net::SocketAddrExt,
+    unix::net::{SocketAddr, UnixStream},
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc,
+};
+
+use adh_rs::protocol::{GUICommand, Protocol, Reply};
+
+#[derive(Clone)]
+pub enum Client {
+    /// A client of the datagram socket with a bound address that we can reply to.
+    Datagram(Box<SocketAddr>),
+    /// A connection to one of the stream sockets or a WebSocket. Its connection thread writes the replies.
+    Connection { id: u64, replies: mpsc::Sender<Reply> },
+}
+
+impl Client {
+    /// A new id for a connection-based client.
+    pub fn next_id() -> u64 {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn send(&self, protocol: &Protocol, reply: &Reply) -> Result<(), anyhow::Error> {
+        match self {
+            Client::Datagram(addr) => protocol.reply(addr, reply),
+            Client::Connection { replies, .. } => Ok(replies.send(reply.clone())?),
+        }
+    }
+
+    pub fn is_same(&self, other: &Client) -> bool {
+        match (self, other) {
+            (Client::Datagram(a), Client::Datagram(b)) => {
+                a.as_abstract_name() == b.as_abstract_name() && a.as_pathname() == b.as_pathname()
+            }
+            (Client::Connection { id: a, .. }, Client::Connection { id: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// The user running the daemon.
+pub fn own_uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// Only the user running the daemon may control it. Clients whose user is unknown are rejected as well.
+pub fn is_same_user(uid: Option<u32>) -> bool {
+    uid == Some(own_uid())
+}
+
+/// Clients on the network (HTTP, OSC and MQTT) are not checked like the clients of the Unix sockets.
+/// They may control the noise, but not stop the daemon.
+pub fn is_allowed_over_network(command: &GUICommand) -> bool {
+    !matches!(command, GUICommand::Quit)
+}
+
+/// The address to listen on for clients on the network. A bare port only listens on the loopback interface,
+/// e.g. `8080` is `127.0.0.1:8080`.
+pub fn listen_address(address: &str) -> Result<std::net::SocketAddr, anyhow::Error> {
+    if let Ok(port) = address.parse() {
+        return Ok(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into());
+    }
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("Invalid address {}", address))
+}
+
+/// The user of the process that connected, from `SO_PEERCRED` because std's `peer_cred` is unstable.
+pub fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::Datagram(addr) => write!(f, "{:?}", addr),
+            Client::Connection { id, .. } => write!(f, "connection {}", id),
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Optional HTTP server to control the noise from a browser, enabled with `--http <address>`.
+//!
+//! - `GET /` serves the web equalizer in `index.html`.
+//! - `GET /api/status` returns the `Status` of the daemon as JSON.
+//! - `POST /api/command` takes a `GUICommand` as JSON (like the JSON socket) and returns the `Reply`.
+//! - `GET /api/ws` opens a WebSocket. Every text message is a command that is answered by a `Reply` message,
+//!   and after `"Subscribe"` events are pushed as well.
+//!
+//! There is no authentication and, unlike on the Unix sockets, no check of the user, so the server should only listen
+//! on localhost unless the network is trusted. Clients can't stop the daemon, see `client::is_allowed_over_network`.
+//! To keep other web pages that the user visits from controlling the daemon, requests are refused unless
+//! - the Host header names the server by an IP address or `localhost` and its port, so that a domain of an attacker
+//!   that resolves to our address (DNS rebinding) is refused,
+//! - the Origin header, which browsers send with cross-origin requests and WebSockets, is missing or the server itself,
+//! - commands are posted as `application/json`, which browsers can't send cross-origin without asking us first.
+//!
+//! The server is deliberately simple: one request per connection and one thread per connection.
+
+use anyhow::anyhow;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use adh_rs::protocol::{decode_json, GUICommand, Reply};
+
+use crate::{
+    client::{self, Client},
+    json_socket::relay_command,
+    DaemonCommand,
+};
+
+const INDEX_HTML: &str = include_str!("../../../index.html");
+/// Bodies of commands are small, anything larger is a mistake or an attack.
+const MAX_BODY_LEN: usize = 64 * 1024;
+/// How long a REST request waits for the event loop to answer.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Defined by RFC 6455 to compute the Sec-WebSocket-Accept header.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Listen on `address` and handle requests in background threads.
+pub fn spawn_server(address: SocketAddr, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(address)?;
+    println!("Serving the web equalizer on http://{}/.", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream, address, tx) {
+                            eprintln!("HTTP connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept HTTP connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    /// Header names are lowercase.
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request, anyhow::Error> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Invalid request line: {}", line.trim()));
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("Connection closed in the middle of the headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let body_len: usize = headers
+        .get("content-length")
+        .map(|len| len.parse())
+        .transpose()?
+        .unwrap_or(0);
+    if body_len > MAX_BODY_LEN {
+        return Err(anyhow!("Request body too large"));
+    }
+    let mut body = vec![0; body_len];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<(), anyhow::Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn respond_json(stream: &mut TcpStream, status: &str, body: &impl serde::Serialize) -> Result<(), anyhow::Error> {
+    respond(stream, status, "application/json", &serde_json::to_vec(body)?)
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    tx: mpsc::Sender<DaemonCommand>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = read_request(&mut reader)?;
+    if let Err(e) = check_origin(&request, address) {
+        eprintln!("Refused HTTP request for {}: {}", request.path, e);
+        return respond(&mut stream, "403 Forbidden", "text/plain", e.to_string().as_bytes());
+    }
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/" | "/index.html") => {
+            respond(&mut stream, "200 OK", "text/html; charset=utf-8", INDEX_HTML.as_bytes())
+        }
+        ("GET", "/api/status") => match execute(GUICommand::GetStatus, &tx)? {
+            Reply::Status(status) => respond_json(&mut stream, "200 OK", &status),
+            reply => respond_json(&mut stream, "500 Internal Server Error", &reply),
+        },
+        ("POST", "/api/command") if !is_json(&request) => respond(
+            &mut stream,
+            "403 Forbidden",
+            "text/plain",
+            b"Commands must be sent as application/json",
+        ),
+        ("POST", "/api/command") => match decode_json::<GUICommand>(&request.body) {
+            Ok(command) => match execute(command, &tx)? {
+                reply @ Reply::Error(_) => respond_json(&mut stream, "400 Bad Request", &reply),
+                reply => respond_json(&mut stream, "200 OK", &reply),
+            },
+            Err(e) => respond_json(
+                &mut stream,
+                "400 Bad Request",
+                &Reply::Error(format!("Invalid command: {}", e)),
+            ),
+        },
+        ("GET", "/api/ws") => handle_websocket(stream, reader, &request, tx),
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found"),
+    }
+}
+
+/// Refuse requests that another web page makes through the browser of the user, see the module documentation.
+fn check_origin(request: &Request, address: SocketAddr) -> Result<(), anyhow::Error> {
+    let host = request.headers.get("host").ok_or(anyhow!("Missing Host header"))?;
+    if !is_own_host(host, address.port()) {
+        return Err(anyhow!("Unexpected Host {}", host));
+    }
+    match request.headers.get("origin") {
+        Some(origin) if *origin != format!("http://{}", host) => Err(anyhow!("Unexpected Origin {}", origin)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `host` is an IP address or `localhost` with the port we listen on.
+/// We can't know our own domain names, but IP addresses and `localhost` can't be taken over by an attacker.
+fn is_own_host(host: &str, port: u16) -> bool {
+    // IPv6 addresses are in brackets, and browsers leave out the default port.
+    let (name, host_port) = match host.strip_prefix('[').and_then(|host| host.split_once(']')) {
+        Some((name, rest)) => (name, rest.strip_prefix(':')),
+        None => match host.rsplit_once(':') {
+            Some((name, host_port)) => (name, Some(host_port)),
+            None => (host, None),
+        },
+    };
+    let host_port = host_port.map_or(Ok(80), str::parse);
+    host_port == Ok(port) && (name == "localhost" || name.parse::<IpAddr>().is_ok())
+}
+
+fn is_json(request: &Request) -> bool {
+    request
+        .headers
+        .get("content-type")
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Pass a command to the event loop and wait for the reply.
+fn execute(command: GUICommand, tx: &mpsc::Sender<DaemonCommand>) -> Result<Reply, anyhow::Error> {
+    if !client::is_allowed_over_network(&command) {
+        return Ok(Reply::Error(format!("{:?} is not allowed over HTTP", command)));
+    }
+    let (replies, replies_rx) = mpsc::channel();
+    let client = Client::Connection {
+        id: Client::next_id(),
+        replies,
+    };
+    tx.send(DaemonCommand::GUI(command, Some(client)))?;
+    Ok(replies_rx.recv_timeout(REPLY_TIMEOUT)?)
+}
+
+/// Upgrade the connection to a WebSocket and relay its messages like lines of the JSON socket.
+fn handle_websocket(
+    mut stream: TcpStream,
+    mut reader: BufReader<TcpStream>,
+    request: &Request,
+    tx: mpsc::Sender<DaemonCommand>,
+) -> Result<(), anyhow::Error> {
+    let Some(key) = request.headers.get("sec-websocket-key") else {
+        return respond(
+            &mut stream,
+            "400 Bad Request",
+            "text/plain",
+            b"Expected a WebSocket handshake",
+        );
+    };
+    let accept = websocket_accept(key);
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )?;
+
+    // Both the reply thread and this thread (for pongs) write frames, so the stream is locked.
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let id = Client::next_id();
+    let (replies, replies_rx) = mpsc::channel::<Reply>();
+    thread::spawn({
+        let writer = writer.clone();
+        move || {
+            for reply in replies_rx {
+                let Ok(json) = serde_json::to_vec(&reply) else {
+                    continue;
+                };
+                if write_frame(&mut writer.lock().unwrap(), OPCODE_TEXT, &json).is_err() {
+                    // Dropping the receiver makes sending to this client fail, so the event loop forgets it.
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut message = Vec::new();
+    loop {
+        let frame = match read_frame(&mut reader) {
+            Ok(frame) => frame,
+            // Browsers do not always send a close frame, e.g. when a phone goes to sleep.
+            Err(e) if e.downcast_ref::<io::Error>().map(io::Error::kind) == Some(io::ErrorKind::UnexpectedEof) => break,
+            Err(e) => return Err(e),
+        };
+        match frame.opcode {
+            OPCODE_TEXT | OPCODE_CONTINUATION => {
+                message.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    relay_command(&String::from_utf8_lossy(&message), id, true, &replies, &tx)?;
+                    message.clear();
+                }
+            }
+            OPCODE_PING => write_frame(&mut writer.lock().unwrap(), OPCODE_PONG, &frame.payload)?,
+            OPCODE_CLOSE => {
+                write_frame(&mut writer.lock().unwrap(), OPCODE_CLOSE, &frame.payload).ok();
+                break;
+            }
+            _ => {}
+        }
+        if message.len() > MAX_BODY_LEN {
+            return Err(anyhow!("WebSocket message too large"));
+        }
+    }
+
+    stream.shutdown(Shutdown::Both).ok();
+    Ok(())
+}
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Read a frame from the client. Client frames are always masked.
+fn read_frame(reader: &mut impl Read) -> Result<Frame, anyhow::Error> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header)?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0F;
+    let masked = header[1] & 0x80 != 0;
+
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    if len > MAX_BODY_LEN {
+        return Err(anyhow!("WebSocket frame too large"));
+    }
+
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// Write an unfragmented frame.
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> Result<(), anyhow::Error> {
+    stream.write_all(&encode_frame(opcode, payload))?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// An unfragmented frame. Server frames are never masked.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// The `Sec-WebSocket-Accept` header for the `Sec-WebSocket-Key` of the client.
+fn websocket_accept(key: &str) -> String {
+    base64(&Sha1::digest(format!("{}{}", key, WEBSOCKET_GUID)))
+}
+
+/// Standard base64 with padding, only needed for the handshake.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: String::from("POST"),
+            path: String::from("/api/command"),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    /// A frame like a browser sends it.
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        let header_len = frame.len() - payload.len();
+        let masked: Vec<_> = payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+        frame.truncate(header_len);
+        frame.extend_from_slice(&mask);
+        frame.extend(masked);
+        frame
+    }
+
+    #[test]
+    fn base64_pads_the_last_group() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn websocket_accept_of_rfc_6455() {
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frame_lengths_round_trip() {
+        for len in [0, 125, 126, 0xFFFF, 0x10000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let bytes = masked_frame(true, OPCODE_TEXT, &payload, [1, 2, 3, 4]);
+            let frame = read_frame(&mut bytes.as_slice()).unwrap();
+            assert!(frame.fin);
+            assert_eq!(frame.opcode, OPCODE_TEXT);
+            assert_eq!(frame.payload, payload, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn encoded_frame_lengths() {
+        assert_eq!(encode_frame(OPCODE_PONG, b"hi"), [0x8A, 2, b'h', b'i']);
+        assert_eq!(encode_frame(OPCODE_TEXT, &[0; 126])[..4], [0x81, 126, 0, 126]);
+        assert_eq!(
+            encode_frame(OPCODE_TEXT, &[0; 0x10000])[..10],
+            [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn fragmented_and_truncated_frames() {
+        let bytes = masked_frame(false, OPCODE_TEXT, b"{\"Toggle", [9, 8, 7, 6]);
+        let frame = read_frame(&mut bytes.as_slice()).unwrap();
+        assert!(!frame.fin);
+        assert_eq!(frame.payload, b"{\"Toggle");
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(read_frame(&mut &truncated[..]).is_err());
+    }
+
+    #[test]
+    fn too_large_frame_is_rejected() {
+        let mut bytes = vec![0x81, 0xFF];
+        bytes.extend_from_slice(&(MAX_BODY_LEN as u64 + 1).to_be_bytes());
+        assert!(read_frame(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn own_hosts() {
+        assert!(is_own_host("127.0.0.1:8787", 8787));
+        assert!(is_own_host("localhost:8787", 8787));
+        assert!(is_own_host("[::1]:8787", 8787));
+        assert!(is_own_host("192.168.1.2", 80));
+        assert!(is_own_host("[::1]", 80));
+        assert!(!is_own_host("127.0.0.1:8788", 8787));
+        assert!(!is_own_host("127.0.0.1", 8787));
+        assert!(!is_own_host("attacker.example:8787", 8787));
+        assert!(!is_own_host("[::1]:x", 8787));
+    }
+
+    #[test]
+    fn origin_must_match_the_host() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 8787));
+        assert!(check_origin(&request(&[("host", "127.0.0.1:8787")]), address).is_ok());
+        assert!(check_origin(
+            &request(&[("host", "127.0.0.1:8787"), ("origin", "http://127.0.0.1:8787")]),
+            address
+        )
+        .is_ok());
+        assert!(check_origin(
+            &request(&[("host", "127.0.0.1:8787"), ("origin", "http://attacker.example")]),
+            address
+        )
+        .is_err());
+        assert!(check_origin(&request(&[("host", "attacker.example:8787")]), address).is_err());
+        assert!(check_origin(&request(&[]), address).is_err());
+    }
+
+    #[test]
+    fn content_type_must_be_json() {
+        assert!(is_json(&request(&[("content-type", "application/json")])));
+        assert!(is_json(&request(&[(
+            "content-type",
+            "Application/JSON; charset=utf-8"
+        )])));
+        assert!(!is_json(&request(&[("content-type", "text/plain")])));
+        assert!(!is_json(&request(&[])));
+    }
+
+    #[test]
+    fn request_with_body() {
+        let raw = "POST /api/command HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\n\"Toggle\"";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/command");
+        assert_eq!(request.headers["host"], "localhost");
+        assert_eq!(request.body, b"\"Toggle\"");
+
+        assert!(read_request(&mut "GET / HTTP/1.1\r\nHost: localhost\r\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Optional text protocol for debugging and for clients in other languages: newline-delimited JSON
+//! over a Unix stream socket, enabled with `--json-socket`.
+//!
+//! Every line is a `GUICommand` in serde's JSON representation and is answered by a line with a `Reply`, e.g.
+//!
+//! ```text
+//! "Toggle"                         -> "Ok"
+//! {"SetVolume": 0.5}               -> "Ok"
+//! {"SetWeights": {"v": [...]}}     -> "Ok"
+//! "GetStatus"                      -> {"Status": {"playing": true, ...}}
+//! "Bogus"                          -> {"Error": "Invalid command: ..."}
+//! ```
+//!
+//! After `"Subscribe"` the connection additionally receives `{"Event": ...}` lines.
+//! Try it with `socat - UNIX-CONNECT:$XDG_RUNTIME_DIR/adh-rs.json.sock`.
+
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::Shutdown;
+use std::os::unix::{
+    fs::PermissionsExt,
+    net::{UnixListener, UnixStream},
+};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use adh_rs::{
+    is_development,
+    protocol::{decode_json, GUICommand, Reply},
+};
+
+use crate::{
+    client::{self, Client},
+    DaemonCommand,
+};
+
+/// Like the datagram socket, in /tmp/ for development and in $XDG_RUNTIME_DIR otherwise.
+fn socket_path() -> PathBuf {
+    if is_development() {
+        Path::new("/tmp/adh-rs.json.sock").to_owned()
+    } else {
+        let xdg_runtime_dir = std::env::var("XDG_RUNTIME_DIR").expect("XDG_RUNTIME_DIR is unset");
+        Path::new(&xdg_runtime_dir).join("adh-rs.json.sock")
+    }
+}
+
+/// Create the socket and accept connections in a background thread.
+pub fn spawn_listener(tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let path = socket_path();
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    println!("Listening for JSON commands on {}.", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let uid = client::peer_uid(&stream).ok();
+                    if !client::is_same_user(uid) {
+                        eprintln!(
+                            "Rejected JSON connection from user {:?}, only user {} may control the daemon.",
+                            uid,
+                            client::own_uid()
+                        );
+                        continue;
+                    }
+                    let tx = tx.clone();
+                    let id = Client::next_id();
+                    println!("Accepted JSON connection {} from user {}.", id, client::own_uid());
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(id, stream, tx) {
+                            eprintln!("JSON connection {} failed: {}", id, e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept JSON connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Relay the commands of one connection into the event loop.
+/// Replies are written by a separate thread because subscribed connections also get events at any time.
+fn handle_connection(id: u64, stream: UnixStream, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let (replies, replies_rx) = mpsc::channel();
+    let writer = stream.try_clone()?;
+    thread::spawn(move || write_replies(writer, replies_rx));
+
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        relay_command(&line, id, false, &replies, &tx)?;
+    }
+
+    // The client closed the connection. Shutting down makes the writer stop at the next reply.
+    stream.shutdown(Shutdown::Both).ok();
+    Ok(())
+}
+
+/// Parse one JSON command and pass it on to the event loop. Invalid commands are answered right away,
+/// as are commands that are not allowed over the network if the client is on the `network`.
+pub fn relay_command(
+    json: &str,
+    id: u64,
+    network: bool,
+    replies: &mpsc::Sender<Reply>,
+    tx: &mpsc::Sender<DaemonCommand>,
+) -> Result<(), anyhow::Error> {
+    match decode_json::<GUICommand>(json.as_bytes()) {
+        Ok(command) if network && !client::is_allowed_over_network(&command) => {
+            replies.send(Reply::Error(format!("{:?} is not allowed over the network", command)))?
+        }
+        Ok(command) => {
+            let client = Client::Connection {
+                id,
+                replies: replies.clone(),
+            };
+            tx.send(DaemonCommand::GUI(command, Some(client)))?;
+        }
+        Err(e) => replies.send(Reply::Error(format!("Invalid command: {}", e)))?,
+    }
+    Ok(())
+}
+
+fn write_replies(stream: UnixStream, replies: mpsc::Receiver<Reply>) {
+    let mut writer = BufWriter::new(stream);
+    for reply in replies {
+        let result = serde_json::to_writer(&mut writer, &reply)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"))
+            .and_then(|()| writer.flush());
+        if result.is_err() {
+            // Dropping the receiver makes sending to this client fail, so the event loop forgets it.
+            return;
+        }
+    }
+}
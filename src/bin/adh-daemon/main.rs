@@ -1,22 +1,45 @@
-use adh_rs::is_development;
+use adh_rs::{audio_bridge, config::Config, is_development, sink::SinkKind};
 use anyhow::anyhow;
-use cpal::traits::StreamTrait;
 use lazy_static::lazy_static;
-use std::os::{fd::FromRawFd, unix::net::UnixDatagram};
+use std::net::SocketAddr;
+use std::os::{
+    fd::FromRawFd,
+    unix::net::{UnixDatagram, UnixListener},
+};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::{
+    mpsc::{self, RecvTimeoutError},
+    Arc,
+};
 use std::thread;
+use std::time::{Duration, Instant};
 use systemd::daemon;
+use xdg::BaseDirectories;
 
+mod client;
+mod http;
+mod json_socket;
+#[cfg(feature = "midi")]
+mod midi;
+mod mpris;
+mod mqtt;
+mod osc;
+mod player;
+mod stream_socket;
+mod subscribers;
 mod tray_icon;
 
-use adh_rs::{
-    audio_bridge::play,
-    protocol::{GUICommand, Protocol},
-    samples::{BlendType, BlendingSamples},
-};
+use adh_rs::protocol::{Event, GUICommand, Protocol, Reply, StreamProtocol};
+use client::Client;
+use mpris::Mpris;
+use mqtt::Mqtt;
+use player::Player;
+use subscribers::Subscribers;
 // use tray_icon::TrayCommand;
 
+/// Replies to connections are written by their own threads, which need a moment before the daemon exits.
+const QUIT_REPLY_GRACE: Duration = Duration::from_millis(100);
+
 lazy_static! {
     /// Which program to start when executing a new GUI process.
     /// During development we use the executable in cargo's target/ directory.
@@ -32,62 +55,266 @@ lazy_static! {
     };
 }
 
+/// Command line arguments of the daemon.
+struct Args {
+    /// Where to play the noise.
+    sink: SinkKind,
+    /// Whether to also accept JSON commands on a stream socket.
+    json_socket: bool,
+    /// Where to serve the web equalizer and the HTTP API, if at all.
+    http: Option<SocketAddr>,
+    /// Where to listen for OSC messages, if at all.
+    osc: Option<SocketAddr>,
+    /// Whether to accept MIDI input.
+    midi: bool,
+}
+
+/// Parse the command line arguments.
+/// `--dev` is handled by `is_development`.
+/// `--sink cpal|wav:<path>|stdout|null` chooses where to play the noise, by default on the sound card via cpal.
+/// `--json-socket` enables the JSON protocol.
+/// `--http <address>` serves the web equalizer, e.g. on `8080` (loopback only) or `0.0.0.0:8080`.
+/// `--osc <address>` listens for OSC messages, e.g. on `9000` (loopback only) or `0.0.0.0:9000`.
+/// `--midi` creates an ALSA sequencer port for MIDI controllers. Needs the `midi` feature.
+fn parse_args() -> Result<Args, anyhow::Error> {
+    let mut sink = SinkKind::Cpal;
+    let mut json_socket = false;
+    let mut http = None;
+    let mut osc = None;
+    let mut midi = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dev" => {}
+            "--sink" => {
+                sink = args.next().ok_or(anyhow!("--sink needs an argument"))?.parse()?;
+            }
+            "--json-socket" => json_socket = true,
+            "--http" => {
+                http = Some(client::listen_address(
+                    &args.next().ok_or(anyhow!("--http needs an address"))?,
+                )?)
+            }
+            "--osc" => {
+                osc = Some(client::listen_address(
+                    &args.next().ok_or(anyhow!("--osc needs an address"))?,
+                )?)
+            }
+            "--midi" if cfg!(feature = "midi") => midi = true,
+            "--midi" => return Err(anyhow!("--midi needs a daemon that is built with the midi feature")),
+            _ => return Err(anyhow!("Unknown command line argument: {}", arg)),
+        }
+    }
+
+    Ok(Args {
+        sink,
+        json_socket,
+        http,
+        osc,
+        midi,
+    })
+}
+
 /// Commands that can be sent to the daemon.
 pub enum DaemonCommand {
     /// Commands from the system tray icon.
     // Tray(TrayCommand),
-    /// Commands from the GUI.
-    GUI(GUICommand),
+    /// Commands from the GUI or other clients, with the client if it wants a reply.
+    GUI(GUICommand, Option<Client>),
+    /// Change single bands of the current weights, e.g. from the faders of a controller.
+    SetBands(Vec<(usize, f32)>),
+    /// A controller of a MIDI controller was moved.
+    MidiControl { channel: u8, controller: u32, value: i32 },
+    /// An error occurred on the audio stream with the given id.
+    StreamError { stream_id: u64, error: String },
 }
 
-/// Create a protocol instance for communicating with the GUI.
-/// In development mode, the daemon creates the socket itself.
-/// Otherwise, we get the socket file descriptor from systemd.
-fn get_protocol() -> Protocol {
+/// Create the sockets for communicating with clients: the datagram socket of `Protocol` and the stream socket of
+/// `StreamProtocol`.
+/// In development mode, the daemon creates the sockets itself.
+/// Otherwise, we get the socket file descriptors from systemd.
+fn get_sockets() -> (Protocol, UnixListener) {
     if is_development() {
-        Protocol::new_recv().unwrap()
-    } else {
-        fn get_systemd_socket() -> Option<Protocol> {
-            let fd = daemon::listen_fds(false).ok()?.iter().next()?;
-            assert!(daemon::is_socket_unix(
-                fd,
-                Some(daemon::SocketType::Datagram),
-                // a.d. TODO the listening check failed, why?
-                daemon::Listening::NoListeningCheck,
-                None::<String>
-            )
-            .unwrap());
-            let sock = unsafe { UnixDatagram::from_raw_fd(fd) };
-            Some(Protocol::new_raw(sock))
-        }
-
-        // If getting the socket from systemd failed, fall back on a manually created one.
-        match get_systemd_socket() {
-            Some(protocol) => protocol,
-            None => {
-                eprintln!("Warning: not in development mode but systemd did not give us a socket. Falling back on manually created socket.");
-                Protocol::new_recv().unwrap()
+        return (Protocol::new_recv().unwrap(), StreamProtocol::listen().unwrap());
+    }
+
+    // systemd passes the sockets in the order of the socket unit, so we tell them apart by their type.
+    let mut datagram = None;
+    let mut stream = None;
+    if let Ok(fds) = daemon::listen_fds(false) {
+        for fd in fds.iter() {
+            let is_unix = |socket_type, listening| {
+                daemon::is_socket_unix(fd, Some(socket_type), listening, None::<String>).unwrap_or(false)
+            };
+            // Datagram sockets never listen, so there is nothing to check.
+            if is_unix(daemon::SocketType::Datagram, daemon::Listening::NoListeningCheck) {
+                datagram = Some(Protocol::new_raw(unsafe { UnixDatagram::from_raw_fd(fd) }).unwrap());
+            } else if is_unix(daemon::SocketType::Stream, daemon::Listening::IsListening) {
+                stream = Some(unsafe { UnixListener::from_raw_fd(fd) });
+            } else {
+                eprintln!("Warning: ignoring unexpected file descriptor {} from systemd.", fd);
             }
         }
     }
+
+    // If getting a socket from systemd failed, fall back on a manually created one.
+    let datagram = datagram.unwrap_or_else(|| {
+        eprintln!("Warning: not in development mode but systemd did not give us a datagram socket. Falling back on manually created socket.");
+        Protocol::new_recv().unwrap()
+    });
+    let stream = stream.unwrap_or_else(|| {
+        eprintln!("Warning: not in development mode but systemd did not give us a stream socket. Falling back on manually created socket.");
+        StreamProtocol::listen().unwrap()
+    });
+    (datagram, stream)
+}
+
+/// The signals that make the daemon quit like the Quit command, e.g. SIGTERM when systemd stops the service.
+fn quit_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+/// Keep the quit signals from killing the daemon, so that `spawn_signal_listener` can handle them.
+/// Threads inherit the signal mask, so this has to happen before any thread is spawned.
+fn block_quit_signals() {
+    let set = quit_signals();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+}
+
+/// Turn the quit signals into Quit commands, so that the noise fades out, the config is written and the sink is
+/// finished (e.g. the header of a WAV file) before the daemon exits.
+fn spawn_signal_listener(tx: mpsc::Sender<DaemonCommand>) {
+    thread::spawn(move || {
+        let set = quit_signals();
+        let mut signal = 0;
+        while unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            println!("Received signal {}, quitting.", signal);
+            if tx.send(DaemonCommand::GUI(GUICommand::Quit, None)).is_err() {
+                return;
+            }
+        }
+    });
 }
 
 /// Because the socket communication is blocking we spawn a thread to push commands received
 /// over the socket also into the mpsc used for communicating with the system tray icon.
 /// (there was an error when trying to use the nonblocking unix socket, maybe look into that again)
-fn gui_relay(tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
-    let protocol = get_protocol();
-
+fn gui_relay(protocol: Arc<Protocol>, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
     loop {
-        let command = protocol.recv().unwrap();
-        println!("Received Command.");
-        tx.send(DaemonCommand::GUI(command))?;
+        let request = protocol.recv().unwrap();
+        if !client::is_same_user(request.uid) {
+            eprintln!(
+                "Rejected command from user {:?}, only user {} may control the daemon.",
+                request.uid,
+                client::own_uid()
+            );
+            let client = request.client.map(|addr| Client::Datagram(Box::new(addr)));
+            send_reply(&protocol, client, Reply::Error(String::from("Permission denied")));
+            continue;
+        }
+        match request.command {
+            Ok(command) => {
+                println!("Received Command.");
+                tx.send(DaemonCommand::GUI(
+                    command,
+                    request.client.map(|addr| Client::Datagram(Box::new(addr))),
+                ))?;
+            }
+            // Tell the client that we did not understand it instead of guessing.
+            Err(e) => {
+                eprintln!("Received invalid command: {}", e);
+                let client = request.client.map(|addr| Client::Datagram(Box::new(addr)));
+                send_reply(&protocol, client, Reply::Error(e.to_string()));
+            }
+        }
+    }
+}
+
+/// Execute a command of the GUI and build the reply for it.
+fn handle_command(player: &mut Player, command: GUICommand) -> Reply {
+    let result = match command {
+        GUICommand::SetWeights(weights) => player.set_weights(weights),
+        GUICommand::SetOutputDevice(device) => player.set_output_device(device),
+        GUICommand::SetVolume(volume) => {
+            player.set_volume(volume);
+            Ok(())
+        }
+        GUICommand::Toggle => {
+            player.toggle();
+            Ok(())
+        }
+        GUICommand::Pause => {
+            player.pause();
+            Ok(())
+        }
+        GUICommand::Resume => {
+            player.resume();
+            Ok(())
+        }
+        GUICommand::SetSleepTimer(duration_secs, fade_secs) => {
+            match (
+                Duration::try_from_secs_f32(duration_secs),
+                Duration::try_from_secs_f32(fade_secs),
+            ) {
+                (Ok(duration), Ok(fade)) => player.set_sleep_timer(duration, fade),
+                _ => Err(anyhow!(
+                    "Invalid sleep timer of {} seconds with a fade of {} seconds",
+                    duration_secs,
+                    fade_secs
+                )),
+            }
+        }
+        GUICommand::CancelSleepTimer => {
+            player.cancel_sleep_timer();
+            Ok(())
+        }
+        GUICommand::SetAlarm(alarm) => player.set_alarm(alarm),
+        GUICommand::ListAlarms => return Reply::Alarms(player.alarms()),
+        GUICommand::CancelAlarm(id) => player.cancel_alarm(id),
+        GUICommand::ListOutputDevices => return Reply::OutputDevices(audio_bridge::list_output_devices()),
+        GUICommand::ListHosts => return Reply::Hosts(audio_bridge::list_hosts()),
+        GUICommand::GetStatus => return Reply::Status(Box::new(player.status())),
+        GUICommand::Hello => return Reply::Hello(env!("CARGO_PKG_VERSION").to_owned()),
+        GUICommand::Unknown => Err(anyhow!("Unknown command, the client is newer than the daemon")),
+        GUICommand::Quit | GUICommand::Subscribe | GUICommand::Unsubscribe | GUICommand::MidiLearn(_) => {
+            unreachable!("Handled by the event loop")
+        }
+    };
+
+    match result {
+        Ok(()) => Reply::Ok,
+        Err(e) => {
+            eprintln!("{}", e);
+            Reply::Error(e.to_string())
+        }
+    }
+}
+
+fn send_reply(protocol: &Protocol, client: Option<Client>, reply: Reply) {
+    if let Some(client) = client {
+        if let Err(e) = client.send(protocol, &reply) {
+            eprintln!("Failed to reply to {}: {}", client, e);
+        }
     }
 }
 
 fn main() -> Result<(), anyhow::Error> {
+    block_quit_signals();
+    let args = parse_args()?;
+    let xdg = BaseDirectories::with_prefix("adh-rs");
+    let config = Config::load_from_disk(&xdg);
+    let sink = args.sink.open(&config)?;
+
     // Create the mpsc that receives both commands from the GUI and the system tray.
     let (tx, rx) = mpsc::channel();
+    spawn_signal_listener(tx.clone());
 
     // Spawn a thread for the system tray icon (gtk somehow takes control of it so it needs to be its own thread).
     // Also span a thread to listen on the socket conntected to the GUI that will relay commands from the socket to the mpsc.
@@ -96,13 +323,51 @@ fn main() -> Result<(), anyhow::Error> {
     //     let tx = tx.clone();
     //     move || tray_icon::main(tx)
     // });
-    thread::spawn(move || gui_relay(tx));
+    // The socket is shared with the relay thread, the event loop uses it to send replies.
+    let (protocol, stream_listener) = get_sockets();
+    let protocol = Arc::new(protocol);
+    thread::spawn({
+        let protocol = protocol.clone();
+        let tx = tx.clone();
+        move || gui_relay(protocol, tx)
+    });
+    stream_socket::spawn_listener(stream_listener, tx.clone());
+    if args.json_socket {
+        json_socket::spawn_listener(tx.clone())?;
+    }
+    if let Some(address) = &args.http {
+        http::spawn_server(*address, tx.clone())?;
+    }
+    if let Some(address) = &args.osc {
+        osc::spawn_listener(*address, tx.clone())?;
+    }
+    #[cfg(feature = "midi")]
+    if args.midi {
+        midi::spawn_listener(tx.clone())?;
+    }
 
-    let mut audio_stream = None;
-    let mut playing = false;
+    let mqtt_config = config.mqtt.clone();
+    let mut player = Player::new(config, xdg, sink, tx.clone());
+    let mut subscribers = Subscribers::default();
+    let mqtt = mqtt_config.map(|mqtt_config| Mqtt::new(mqtt_config, tx.clone(), &player.status()));
+    // Media keys and desktop widgets are nice to have, so we keep going without a session bus.
+    let mpris = match Mpris::new(tx, player.status()) {
+        Ok(mpris) => Some(mpris),
+        Err(e) => {
+            eprintln!("MPRIS is not available: {}", e);
+            None
+        }
+    };
 
     loop {
-        let command = rx.recv();
+        // If the player has something to do at a later time we only wait until then.
+        let command = match player.next_deadline() {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(RecvTimeoutError::from),
+        };
+        // Remember the state so that we can tell subscribers what changed.
+        let before = player.status();
+
         match command {
             // We cannot run the GUI as a separate thread because iced wants to be tha main thread.
             // So we spawn a new process.
@@ -122,46 +387,79 @@ fn main() -> Result<(), anyhow::Error> {
             //     }
             // }
             // Quit command can come from both the GUI and the system tray icon.
-            // Returning from the main function here will the threads we spawned.
-            Ok(/*DaemonCommand::Tray(TrayCommand::Quit) | */ DaemonCommand::GUI(GUICommand::Quit)) => {
+            // We fade out the noise first. Returning from the main function here will the threads we spawned.
+            Ok(/*DaemonCommand::Tray(TrayCommand::Quit) | */ DaemonCommand::GUI(GUICommand::Quit, client)) => {
+                send_reply(&protocol, client, Reply::Ok);
+                player.quit();
+                thread::sleep(QUIT_REPLY_GRACE);
                 println!("Daemon quit");
                 return Ok(());
             }
-            // When receiving weights from the GUI we generate some noise chunks and create
-            // a new audio stream that continuously plays the chunks blending between them.
-            Ok(DaemonCommand::GUI(GUICommand::SetWeights(weights))) => {
-                let samples1 = adh_rs::generator::gen_weighted_noise(&weights);
-                let samples2 = adh_rs::generator::gen_weighted_noise(&weights);
-                let chunks = BlendingSamples::new(vec![samples1, samples2])
-                    .unwrap()
-                    .with_blend(BlendType::Sigmoid);
-
-                match play(chunks) {
-                    Ok(new_audio_stream) => {
-                        playing = true;
-                        audio_stream = Some(new_audio_stream);
+            Ok(DaemonCommand::GUI(GUICommand::Subscribe, client)) => {
+                let reply = match &client {
+                    Some(client) => {
+                        subscribers.subscribe(client);
+                        Reply::Ok
                     }
-                    Err(e) => eprintln!("{}", e),
+                    None => Reply::Error(String::from("Subscribing needs a bound socket to send events to.")),
+                };
+                send_reply(&protocol, client, reply);
+            }
+            Ok(DaemonCommand::GUI(GUICommand::Unsubscribe, client)) => {
+                if let Some(client) = &client {
+                    subscribers.unsubscribe(client);
                 }
+                send_reply(&protocol, client, Reply::Ok);
             }
-            // Some backends support pausing playback of the audio stream so we try it here.
-            Ok(DaemonCommand::GUI(GUICommand::Toggle) /* | DaemonCommand::Tray(TrayCommand::Toggle)*/) => {
-                if let Some(audio_stream) = &audio_stream {
-                    let res = if playing {
-                        audio_stream.stream.pause().map_err(|e| anyhow!(e))
-                    } else {
-                        audio_stream.stream.play().map_err(|e| anyhow!(e))
-                    };
-
-                    // Only update status if toggling was successful.
-                    match res {
-                        Ok(()) => playing = !playing,
-                        Err(e) => eprintln!("{}", e),
-                    }
+            Ok(DaemonCommand::GUI(GUICommand::MidiLearn(target), client)) => {
+                let reply = if args.midi {
+                    player.learn_midi(target);
+                    Reply::Ok
+                } else {
+                    Reply::Error(String::from("MIDI input is not enabled, start the daemon with --midi."))
+                };
+                send_reply(&protocol, client, reply);
+            }
+            Ok(DaemonCommand::GUI(command, client) /* | DaemonCommand::Tray(TrayCommand::Toggle)*/) => {
+                let reply = handle_command(&mut player, command);
+                send_reply(&protocol, client, reply);
+            }
+            Ok(DaemonCommand::MidiControl {
+                channel,
+                controller,
+                value,
+            }) => player.on_midi_control(channel, controller, value),
+            Ok(DaemonCommand::SetBands(bands)) => {
+                if let Err(e) = player.set_bands(&bands) {
+                    eprintln!("{}", e);
                 }
             }
-            Err(e) => {
-                eprintln!("{}", e);
+            Ok(DaemonCommand::StreamError { stream_id, error }) => player.on_stream_error(stream_id, error),
+            Err(RecvTimeoutError::Timeout) => {}
+            // Can't happen while the player holds a sender, but waiting would return right away forever.
+            Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("The event loop has no senders left")),
+        }
+        // Checked after every message and not only on timeouts, so that a steady stream of commands does not
+        // starve fades, alarms and recovery.
+        if player
+            .next_deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            player.on_deadline();
+        }
+
+        let after = player.status();
+        let mut events = player.take_events();
+        events.extend(Event::changes(&before, &after));
+        if !subscribers.is_empty() {
+            subscribers.notify(&protocol, &events);
+        }
+        if let Some(mqtt) = &mqtt {
+            mqtt.update(&after);
+        }
+        if let Some(mpris) = &mpris {
+            if let Err(e) = mpris.update(&after) {
+                eprintln!("Failed to update MPRIS: {}", e);
             }
         }
     }
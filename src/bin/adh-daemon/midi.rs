@@ -0,0 +1,103 @@
+//! Optional MIDI input via the ALSA sequencer, enabled with `--midi`, to control the bands and the volume with the
+//! faders and knobs of a MIDI controller.
+//!
+//! The daemon creates the sequencer port `adh-rs:input`. Connect a controller to it, e.g. with
+//! `aconnect "nanoKONTROL2" adh-rs`. Controllers are mapped to targets by learning: after
+//! `adh-ctl midi learn band 7` the next controller that is moved changes band 7 from then on.
+//! The mappings are stored in the config.
+//!
+//! Only built with the `midi` feature, because it needs libasound.
+//!
+//! Without a hardware controller, load the `snd-virmidi` kernel module, connect its port to ours and send control
+//! changes to it, e.g. with `amidi -p virmidi -S "B0 07 40"` (controller 7 on channel 0 to value 64).
+
+use std::ffi::CString;
+use std::sync::mpsc;
+use std::thread;
+
+use alsa::seq::{EvCtrl, EventType, Input, PortCap, PortType, Seq};
+
+use crate::DaemonCommand;
+
+/// Open the sequencer and relay control changes in a background thread.
+pub fn spawn_listener(tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let seq = Seq::open(None, Some(alsa::Direction::Capture), false)?;
+    seq.set_client_name(&CString::new("adh-rs")?)?;
+    let port = seq.create_simple_port(
+        &CString::new("input")?,
+        PortCap::WRITE | PortCap::SUBS_WRITE,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )?;
+    println!("Listening for MIDI on sequencer port {}:{}.", seq.client_id()?, port);
+
+    thread::spawn(move || {
+        if let Err(e) = relay(seq, tx) {
+            eprintln!("MIDI input failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn relay(seq: Seq, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let mut input = seq.input();
+    relay_controls(std::iter::repeat_with(|| next_control(&mut input)), &tx)
+}
+
+/// Wait for the next event of the sequencer. Returns None for events that are not control changes.
+fn next_control(input: &mut Input) -> Result<Option<EvCtrl>, anyhow::Error> {
+    let event = input.event_input()?;
+    if event.get_type() != EventType::Controller {
+        return Ok(None);
+    }
+    Ok(event.get_data())
+}
+
+/// Pass the control changes to the event loop until reading one fails. Tests feed the events directly.
+fn relay_controls(
+    controls: impl IntoIterator<Item = Result<Option<EvCtrl>, anyhow::Error>>,
+    tx: &mpsc::Sender<DaemonCommand>,
+) -> Result<(), anyhow::Error> {
+    for control in controls {
+        if let Some(EvCtrl { channel, param, value }) = control? {
+            tx.send(DaemonCommand::MidiControl {
+                channel,
+                controller: param,
+                value,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn control_changes_are_relayed() {
+        let (tx, rx) = mpsc::channel();
+        let control = |param, value| {
+            Ok(Some(EvCtrl {
+                channel: 1,
+                param,
+                value,
+            }))
+        };
+        let result = relay_controls([control(7, 64), Ok(None), control(0, 127), Err(anyhow!("Gone"))], &tx);
+        assert!(result.is_err());
+
+        let relayed: Vec<_> = rx
+            .try_iter()
+            .map(|command| match command {
+                DaemonCommand::MidiControl {
+                    channel,
+                    controller,
+                    value,
+                } => (channel, controller, value),
+                _ => panic!("Expected a control change"),
+            })
+            .collect();
+        assert_eq!(relayed, [(1, 7, 64), (1, 0, 127)]);
+    }
+}
@@ -0,0 +1,414 @@
+//! MPRIS2 player on the D-Bus session bus, so that media keys and desktop widgets can control the noise.
+//!
+//! The D-Bus methods are called on zbus' own threads, so they only forward commands into the event loop.
+//! The properties are read from the last status that the event loop gave us in `Mpris::update`,
+//! which also emits the PropertiesChanged signals.
+//!
+//! Test it against a private bus:
+//! ```text
+//! $ eval $(dbus-daemon --session --print-address --fork | sed 's/^/export DBUS_SESSION_BUS_ADDRESS=/')
+//! $ adh-daemon --dev &
+//! $ busctl --user call org.mpris.MediaPlayer2.adh_rs /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player PlayPause
+//! ```
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+use zbus::{
+    blocking::{connection, Connection},
+    interface,
+    zvariant::{ObjectPath, OwnedValue, Value},
+};
+
+use adh_rs::protocol::{GUICommand, Status};
+
+use crate::DaemonCommand;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.adh_rs";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+/// MPRIS needs a track id. We only ever have one "track", the noise.
+const TRACK_ID: &str = "/org/adh_rs/noise";
+
+pub struct Mpris {
+    connection: Connection,
+}
+
+impl Mpris {
+    pub fn new(tx: mpsc::Sender<DaemonCommand>, status: Status) -> Result<Self, anyhow::Error> {
+        Self::serve(connection::Builder::session()?, tx, status)
+    }
+
+    /// Serve the player on the bus of `builder`. Tests pass a private bus.
+    fn serve(
+        builder: connection::Builder,
+        tx: mpsc::Sender<DaemonCommand>,
+        status: Status,
+    ) -> Result<Self, anyhow::Error> {
+        let connection = builder
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, Root { tx: tx.clone() })?
+            .serve_at(OBJECT_PATH, Player { tx, status })?
+            .build()?;
+        println!("Registered MPRIS player {}.", BUS_NAME);
+
+        Ok(Self { connection })
+    }
+
+    /// Show the new status and tell D-Bus which properties changed.
+    pub fn update(&self, status: &Status) -> Result<(), anyhow::Error> {
+        let iface = self.connection.object_server().interface::<_, Player>(OBJECT_PATH)?;
+        let old = std::mem::replace(&mut iface.get_mut().status, status.clone());
+
+        let player = iface.get();
+        let ctxt = iface.signal_context();
+        zbus::block_on(async {
+            if old.playing != status.playing || old.weights.is_some() != status.weights.is_some() {
+                player.playback_status_changed(ctxt).await?;
+            }
+            if old.volume != status.volume {
+                player.volume_changed(ctxt).await?;
+            }
+            if old.preset != status.preset || old.weights.is_some() != status.weights.is_some() {
+                player.metadata_changed(ctxt).await?;
+            }
+            Ok::<(), zbus::Error>(())
+        })?;
+        Ok(())
+    }
+}
+
+fn playback_status(status: &Status) -> &'static str {
+    match (status.playing, status.weights.is_some()) {
+        (true, _) => "Playing",
+        (false, true) => "Paused",
+        (false, false) => "Stopped",
+    }
+}
+
+/// The title of the "track", None if there is no noise yet.
+fn title(status: &Status) -> Option<String> {
+    status.weights.as_ref()?;
+    Some(match &status.preset {
+        Some(preset) => format!("{} noise", preset),
+        None => String::from("Custom noise"),
+    })
+}
+
+/// MPRIS volumes may be negative, which means silence, or above 1, which we cannot play louder.
+/// None for NaN, which is no volume at all.
+fn volume_from_mpris(volume: f64) -> Option<f32> {
+    if volume.is_nan() {
+        return None;
+    }
+    Some(volume.clamp(0.0, 1.0) as f32)
+}
+
+/// The `org.mpris.MediaPlayer2` interface that describes the application.
+struct Root {
+    tx: mpsc::Sender<DaemonCommand>,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {
+        self.tx.send(DaemonCommand::GUI(GUICommand::Quit, None)).ok();
+    }
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "adh-rs noise"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// The `org.mpris.MediaPlayer2.Player` interface that controls playback.
+/// Stopping is the same as pausing because we want to keep the noise to resume it later.
+struct Player {
+    tx: mpsc::Sender<DaemonCommand>,
+    status: Status,
+}
+
+impl Player {
+    fn send(&self, command: GUICommand) {
+        self.tx.send(DaemonCommand::GUI(command, None)).ok();
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn play_pause(&self) {
+        self.send(GUICommand::Toggle);
+    }
+
+    fn play(&self) {
+        self.send(GUICommand::Resume);
+    }
+
+    fn pause(&self) {
+        self.send(GUICommand::Pause);
+    }
+
+    fn stop(&self) {
+        self.send(GUICommand::Pause);
+    }
+
+    // Noise has no tracks or position, so these do nothing.
+    fn next(&self) {}
+
+    fn previous(&self) {}
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) {}
+
+    fn open_uri(&self, _uri: &str) {}
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        playback_status(&self.status)
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.status.volume as f64
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) {
+        if let Some(volume) = volume_from_mpris(volume) {
+            self.send(GUICommand::SetVolume(volume));
+        }
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let mut metadata = HashMap::new();
+        let Some(title) = title(&self.status) else {
+            return metadata;
+        };
+        let values = [
+            (
+                "mpris:trackid",
+                Value::from(ObjectPath::from_static_str_unchecked(TRACK_ID)),
+            ),
+            ("xesam:title", Value::from(title)),
+            ("xesam:artist", Value::from(vec!["adh-rs"])),
+        ];
+        for (key, value) in values {
+            if let Ok(value) = value.try_to_owned() {
+                metadata.insert(key.to_owned(), value);
+            }
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        self.status.weights.is_some()
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use adh_rs::Weights;
+    use zbus::{blocking::fdo::PropertiesProxy, names::InterfaceName};
+
+    fn status(weights: Option<Weights>, preset: Option<&str>) -> Status {
+        Status {
+            playing: false,
+            weights,
+            volume: 0.5,
+            output_device: None,
+            stream: None,
+            uptime_secs: 0.0,
+            preset: preset.map(String::from),
+            sleep_timer: None,
+        }
+    }
+
+    #[test]
+    fn title_names_the_preset() {
+        let weights = Some(Weights::default());
+        assert_eq!(title(&status(None, None)), None);
+        assert_eq!(title(&status(None, Some("pink"))), None);
+        assert_eq!(title(&status(weights, Some("pink"))).as_deref(), Some("pink noise"));
+        assert_eq!(title(&status(weights, None)).as_deref(), Some("Custom noise"));
+    }
+
+    #[test]
+    fn playback_status_without_noise_is_stopped() {
+        let mut status = status(None, None);
+        assert_eq!(playback_status(&status), "Stopped");
+        status.weights = Some(Weights::default());
+        assert_eq!(playback_status(&status), "Paused");
+        status.playing = true;
+        assert_eq!(playback_status(&status), "Playing");
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        assert_eq!(volume_from_mpris(0.25), Some(0.25));
+        assert_eq!(volume_from_mpris(-1.0), Some(0.0));
+        assert_eq!(volume_from_mpris(2.0), Some(1.0));
+        assert_eq!(volume_from_mpris(f64::NAN), None);
+    }
+
+    /// A `dbus-daemon` for this test only, killed when dropped.
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        /// None if there is no `dbus-daemon` to start.
+        fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?).read_line(&mut address).ok()?;
+            Some(Self {
+                daemon,
+                address: address.trim().to_owned(),
+            })
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            self.daemon.kill().ok();
+            self.daemon.wait().ok();
+        }
+    }
+
+    #[test]
+    fn serves_the_player_on_the_bus() -> Result<(), anyhow::Error> {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipped, dbus-daemon is not on PATH");
+            return Ok(());
+        };
+        let (tx, rx) = mpsc::channel();
+        let mpris = Mpris::serve(
+            connection::Builder::address(bus.address.as_str())?,
+            tx,
+            status(Some(Weights::default()), Some("pink")),
+        )?;
+        let client = connection::Builder::address(bus.address.as_str())?.build()?;
+
+        let call = |method| {
+            client.call_method(
+                Some(BUS_NAME),
+                OBJECT_PATH,
+                Some("org.mpris.MediaPlayer2.Player"),
+                method,
+                &(),
+            )
+        };
+        let received = || match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(DaemonCommand::GUI(command, None)) => command,
+            _ => panic!("Expected a command from the player"),
+        };
+        call("PlayPause")?;
+        assert!(matches!(received(), GUICommand::Toggle));
+        call("Stop")?;
+        assert!(matches!(received(), GUICommand::Pause));
+
+        let properties = PropertiesProxy::builder(&client)
+            .destination(BUS_NAME)?
+            .path(OBJECT_PATH)?
+            .build()?;
+        let player: InterfaceName = "org.mpris.MediaPlayer2.Player".try_into()?;
+        properties.set(player.clone(), "Volume", &Value::from(0.25))?;
+        assert!(matches!(received(), GUICommand::SetVolume(volume) if volume == 0.25));
+        assert_eq!(f64::try_from(properties.get(player.clone(), "Volume")?)?, 0.5);
+
+        let metadata: HashMap<String, OwnedValue> = properties.get(player.clone(), "Metadata")?.try_into()?;
+        assert_eq!(String::try_from(metadata["xesam:title"].try_clone()?)?, "pink noise");
+
+        mpris.update(&status(Some(Weights::default()), Some("brown")))?;
+        let metadata: HashMap<String, OwnedValue> = properties.get(player, "Metadata")?.try_into()?;
+        assert_eq!(String::try_from(metadata["xesam:title"].try_clone()?)?, "brown noise");
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+//! Optional MQTT client for home automation, enabled by the `mqtt` section of the config.
+//!
+//! With the default topic `adh-rs` the daemon publishes (retained)
+//!
+//! ```text
+//! adh-rs/availability   "online", or "offline" as the last will when the daemon goes away
+//! adh-rs/state          {"playing": true, "preset": "brown", "volume": 0.5}, the preset is "custom" for other weights
+//! ```
+//!
+//! and reacts to
+//!
+//! ```text
+//! adh-rs/set/playing    "ON" to resume, "OFF" to pause
+//! adh-rs/set/volume     the volume in 0..1
+//! adh-rs/set/preset     the name of a preset, e.g. "brown", which also starts playing
+//! adh-rs/command        any command in the JSON format of the JSON socket, except "Quit"
+//! ```
+//!
+//! Anyone who may publish to these topics controls the noise, so restrict them with the ACLs of the broker.
+//!
+//! Home Assistant discovers a switch, a volume number and a preset select from the discovery messages.
+//! The client speaks just enough MQTT 3.1.1 for this: QoS 0 only and a reconnect when the connection fails.
+//! Test it with `mosquitto -v` and `mosquitto_sub -v -t 'adh-rs/#' -t 'homeassistant/#'`.
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use adh_rs::{
+    config::MqttConfig,
+    presets::{preset, PRESET_NAMES},
+    protocol::{decode_json, GUICommand, Status},
+};
+
+use crate::{client, DaemonCommand};
+
+/// We ping the broker at half this interval so that it knows we are still there.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+/// Commands are short, anything larger is not for us.
+const PACKET_MAX_LEN: usize = 64 * 1024;
+/// The preset we publish when the weights are not a preset. Home Assistant only shows values that are an option.
+const CUSTOM_PRESET: &str = "custom";
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+
+/// Forwards the status of the daemon to the MQTT thread, like `Mpris::update`.
+pub struct Mqtt {
+    statuses: mpsc::Sender<Status>,
+}
+
+/// What we publish on the state topic. The rest of the status changes too often to be interesting.
+#[derive(PartialEq, Serialize)]
+struct State {
+    playing: bool,
+    preset: String,
+    volume: f32,
+}
+
+impl From<&Status> for State {
+    fn from(status: &Status) -> Self {
+        Self {
+            playing: status.playing,
+            preset: status.preset.clone().unwrap_or_else(|| CUSTOM_PRESET.to_owned()),
+            volume: status.volume,
+        }
+    }
+}
+
+impl Mqtt {
+    /// Connect to the broker in a background thread, which keeps reconnecting if that fails.
+    pub fn new(config: MqttConfig, tx: mpsc::Sender<DaemonCommand>, status: &Status) -> Self {
+        let (statuses, statuses_rx) = mpsc::channel();
+        let state = State::from(status);
+        thread::spawn(move || run(config, tx, statuses_rx, state));
+        Self { statuses }
+    }
+
+    pub fn update(&self, status: &Status) {
+        self.statuses.send(status.clone()).ok();
+    }
+}
+
+fn run(config: MqttConfig, tx: mpsc::Sender<DaemonCommand>, statuses: mpsc::Receiver<Status>, mut state: State) {
+    loop {
+        match session(&config, &tx, &statuses, &mut state) {
+            Ok(()) => return,
+            Err(e) => eprintln!("MQTT connection to {} failed: {}", config.broker, e),
+        }
+
+        thread::sleep(RECONNECT_INTERVAL);
+        // Only the latest state is interesting after reconnecting.
+        while let Ok(status) = statuses.try_recv() {
+            state = State::from(&status);
+        }
+    }
+}
+
+/// One connection to the broker. Returns Ok if the daemon is quitting.
+fn session(
+    config: &MqttConfig,
+    tx: &mpsc::Sender<DaemonCommand>,
+    statuses: &mpsc::Receiver<Status>,
+    state: &mut State,
+) -> Result<(), anyhow::Error> {
+    let mut stream = TcpStream::connect(&config.broker)?;
+    let availability_topic = format!("{}/availability", config.topic);
+    let state_topic = format!("{}/state", config.topic);
+
+    write_packet(&mut stream, CONNECT, &connect_packet(config, &availability_topic))?;
+    let (header, body) = read_packet(&mut stream)?;
+    if header & 0xF0 != CONNACK || body.get(1) != Some(&0) {
+        return Err(anyhow!(
+            "The broker refused the connection (return code {:?})",
+            body.get(1)
+        ));
+    }
+    println!("Connected to the MQTT broker {}.", config.broker);
+
+    if let Some(discovery_prefix) = &config.discovery_prefix {
+        for (topic, payload) in discovery_messages(&config.topic, discovery_prefix) {
+            publish(&mut stream, &topic, payload.to_string().as_bytes(), true)?;
+        }
+    }
+    publish(&mut stream, &availability_topic, b"online", true)?;
+    publish(&mut stream, &state_topic, &serde_json::to_vec(state)?, true)?;
+
+    let filters = [format!("{}/set/+", config.topic), format!("{}/command", config.topic)];
+    let mut subscribe = vec![0, 1]; // The packet identifier.
+    for filter in &filters {
+        subscribe.extend_from_slice(&encode_string(filter));
+        subscribe.push(0); // QoS 0
+    }
+    write_packet(&mut stream, SUBSCRIBE, &subscribe)?;
+
+    // The reader shuts the connection down when it fails, so that the next write here fails and we reconnect.
+    let reader = stream.try_clone()?;
+    thread::spawn({
+        let tx = tx.clone();
+        let topic = config.topic.clone();
+        move || {
+            if let Err(e) = read_commands(&reader, &topic, &filters, &tx) {
+                eprintln!("Reading from the MQTT broker failed: {}", e);
+            }
+            reader.shutdown(Shutdown::Both).ok();
+        }
+    });
+
+    let mut last_write = Instant::now();
+    loop {
+        match statuses.recv_timeout((KEEP_ALIVE / 2).saturating_sub(last_write.elapsed())) {
+            Ok(status) => {
+                let new_state = State::from(&status);
+                if new_state != *state {
+                    *state = new_state;
+                    publish(&mut stream, &state_topic, &serde_json::to_vec(state)?, true)?;
+                    last_write = Instant::now();
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                write_packet(&mut stream, PINGREQ, &[])?;
+                last_write = Instant::now();
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Relay the messages on our command topics into the event loop.
+fn read_commands(
+    mut stream: &TcpStream,
+    topic: &str,
+    filters: &[String],
+    tx: &mpsc::Sender<DaemonCommand>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        let (header, body) = read_packet(&mut stream)?;
+        if header & 0xF0 == SUBACK {
+            // A return code per filter after the packet identifier, 0x80 if the broker refused it, e.g. by its ACL.
+            // Subscribing again would not help, so we keep publishing the state.
+            for (filter, code) in filters.iter().zip(body.iter().skip(2)) {
+                if *code == 0x80 {
+                    eprintln!("The MQTT broker refused the subscription to {}.", filter);
+                }
+            }
+            continue;
+        }
+        // Everything else are acknowledgements that we do not need.
+        if header & 0xF0 != PUBLISH {
+            continue;
+        }
+
+        let topic_len = match body.get(..2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => return Err(anyhow!("Invalid PUBLISH")),
+        };
+        let message_topic = String::from_utf8_lossy(body.get(2..2 + topic_len).ok_or(anyhow!("Invalid PUBLISH"))?);
+        // Messages with a QoS above 0 have a packet identifier, but the broker only sends QoS 0 to us.
+        let payload_start = 2 + topic_len + if header & 0x06 != 0 { 2 } else { 0 };
+        let payload = String::from_utf8_lossy(body.get(payload_start..).unwrap_or_default());
+        let payload = payload.trim();
+
+        let command = match message_topic.strip_prefix(topic) {
+            Some("/set/playing") => match payload {
+                "ON" => Some(GUICommand::Resume),
+                "OFF" => Some(GUICommand::Pause),
+                _ => None,
+            },
+            Some("/set/volume") => payload
+                .parse()
+                .ok()
+                .map(|volume: f32| GUICommand::SetVolume(volume.clamp(0.0, 1.0))),
+            // Choosing custom weights in Home Assistant does not tell us which ones.
+            Some("/set/preset") if payload == CUSTOM_PRESET => continue,
+            Some("/set/preset") => preset(payload).map(GUICommand::SetWeights),
+            Some("/command") => decode_json(payload.as_bytes())
+                .ok()
+                .filter(client::is_allowed_over_network),
+            _ => continue,
+        };
+        match command {
+            Some(command) => tx.send(DaemonCommand::GUI(command, None))?,
+            None => eprintln!("Invalid MQTT message on {}: {}", message_topic, payload),
+        }
+    }
+}
+
+/// The Home Assistant discovery messages for a switch, the volume and the preset, as (topic, payload).
+fn discovery_messages(topic: &str, discovery_prefix: &str) -> Vec<(String, serde_json::Value)> {
+    let node_id = topic.replace('/', "_");
+    let device = json!({
+        "identifiers": [node_id],
+        "name": "adh-rs noise",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let availability_topic = format!("{}/availability", topic);
+    let state_topic = format!("{}/state", topic);
+
+    let entities = [
+        (
+            "switch",
+            "playing",
+            json!({
+                "name": "Noise",
+                "command_topic": format!("{}/set/playing", topic),
+                "value_template": "{{ 'ON' if value_json.playing else 'OFF' }}",
+            }),
+        ),
+        (
+            "number",
+            "volume",
+            json!({
+                "name": "Volume",
+                "command_topic": format!("{}/set/volume", topic),
+                "value_template": "{{ value_json.volume }}",
+                "min": 0,
+                "max": 1,
+                "step": 0.01,
+            }),
+        ),
+        (
+            "select",
+            "preset",
+            json!({
+                "name": "Preset",
+                "command_topic": format!("{}/set/preset", topic),
+                "value_template": "{{ value_json.preset }}",
+                "options": PRESET_NAMES.iter().chain([&CUSTOM_PRESET]).collect::<Vec<_>>(),
+            }),
+        ),
+    ];
+
+    entities
+        .into_iter()
+        .map(|(component, object_id, mut payload)| {
+            payload["unique_id"] = json!(format!("{}_{}", node_id, object_id));
+            payload["state_topic"] = json!(state_topic);
+            payload["availability_topic"] = json!(availability_topic);
+            payload["device"] = device.clone();
+            (
+                format!("{}/{}/{}/{}/config", discovery_prefix, component, node_id, object_id),
+                payload,
+            )
+        })
+        .collect()
+}
+
+/// The body of the CONNECT packet, with "offline" as the last will.
+fn connect_packet(config: &MqttConfig, availability_topic: &str) -> Vec<u8> {
+    // Clean session, a retained will with QoS 0, and the credentials if there are any.
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = encode_string("MQTT");
+    body.push(4); // MQTT 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    body.extend_from_slice(&encode_string(&format!("adh-rs-{}", std::process::id())));
+    body.extend_from_slice(&encode_string(availability_topic));
+    body.extend_from_slice(&encode_string("offline"));
+    for credential in [&config.username, &config.password].into_iter().flatten() {
+        body.extend_from_slice(&encode_string(credential));
+    }
+    body
+}
+
+fn publish(stream: &mut TcpStream, topic: &str, payload: &[u8], retain: bool) -> Result<(), anyhow::Error> {
+    let mut body = encode_string(topic);
+    body.extend_from_slice(payload);
+    write_packet(stream, PUBLISH | retain as u8, &body)
+}
+
+fn encode_string(string: &str) -> Vec<u8> {
+    let mut encoded = (string.len() as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(string.as_bytes());
+    encoded
+}
+
+fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> Result<(), anyhow::Error> {
+    stream.write_all(&encode_packet(header, body))?;
+    Ok(())
+}
+
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    // The remaining length is encoded with 7 bits per byte, the high bit means that more bytes follow.
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Read a packet and return its first byte (type and flags) and its body.
+fn read_packet(stream: &mut impl Read) -> Result<(u8, Vec<u8>), anyhow::Error> {
+    let mut byte = [0; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut len = 0;
+    for shift in [0, 7, 14, 21] {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if len > PACKET_MAX_LEN {
+        return Err(anyhow!("MQTT packet of {} bytes is too big", len));
+    }
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length_boundaries() {
+        let cases: [(usize, &[u8]); 6] = [
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16383, &[0xFF, 0x7F]),
+            (16384, &[0x80, 0x80, 0x01]),
+            (PACKET_MAX_LEN, &[0x80, 0x80, 0x04]),
+        ];
+        for (len, encoded_len) in cases {
+            let body: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let packet = encode_packet(PUBLISH, &body);
+            assert_eq!(&packet[1..1 + encoded_len.len()], encoded_len, "{} bytes", len);
+            assert_eq!(packet.len(), 1 + encoded_len.len() + len);
+
+            let (header, read_body) = read_packet(&mut packet.as_slice()).unwrap();
+            assert_eq!(header, PUBLISH);
+            assert_eq!(read_body, body, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn too_large_and_truncated_packets_are_rejected() {
+        let packet = encode_packet(PUBLISH, &vec![0; PACKET_MAX_LEN + 1]);
+        assert!(read_packet(&mut packet.as_slice()).is_err());
+
+        let packet = encode_packet(PUBLISH, b"payload");
+        assert!(read_packet(&mut &packet[..packet.len() - 1]).is_err());
+        assert!(read_packet(&mut &[PUBLISH, 0x80][..]).is_err());
+    }
+
+    #[test]
+    fn strings_have_a_length_prefix() {
+        assert_eq!(encode_string(""), [0, 0]);
+        assert_eq!(encode_string("MQTT"), [0, 4, b'M', b'Q', b'T', b'T']);
+    }
+
+    #[test]
+    fn connect_flags() {
+        let config = MqttConfig::default();
+        let body = connect_packet(&config, "adh-rs/availability");
+        // After the protocol name and level: clean session, will and retained will.
+        assert_eq!(&body[..7], &[0, 4, b'M', b'Q', b'T', b'T', 4]);
+        assert_eq!(body[7], 0x26);
+
+        let config = MqttConfig {
+            username: Some(String::from("user")),
+            password: Some(String::from("secret")),
+            ..MqttConfig::default()
+        };
+        let body = connect_packet(&config, "adh-rs/availability");
+        assert_eq!(body[7], 0xE6);
+        assert!(body.ends_with(&[&encode_string("user")[..], &encode_string("secret")].concat()));
+    }
+
+    #[test]
+    fn discovery_offers_the_custom_preset() {
+        let messages = discovery_messages("adh-rs", "homeassistant");
+        let select = messages
+            .iter()
+            .find(|(topic, _)| topic.starts_with("homeassistant/select/"))
+            .map(|(_, payload)| payload)
+            .unwrap();
+        let options = select["options"].as_array().unwrap();
+        assert!(options.contains(&json!(CUSTOM_PRESET)));
+        assert!(options.contains(&json!("brown")));
+    }
+}
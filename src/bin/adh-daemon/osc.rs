@@ -0,0 +1,354 @@
+//! Optional Open Sound Control input over UDP, enabled with `--osc <address>`, e.g. for TouchOSC or live coding.
+//!
+//! ```text
+//! /adh/band/<0-31> <0..1>   set the weight of one frequency band
+//! /adh/volume <0..1>        set the master volume
+//! /adh/toggle               pause or resume
+//! /adh/pause
+//! /adh/resume
+//! /adh/preset/<name>        play a preset, e.g. /adh/preset/brown
+//! ```
+//!
+//! Buttons of controllers usually send 1 when pressed and 0 when released, so messages with an argument of 0 are
+//! ignored for the commands without a value. Bundles are unpacked but their time tags are ignored.
+//! Try it with `oscsend localhost 9000 /adh/band/3 f 0.5`.
+//!
+//! UDP has no notion of users, so anyone who can reach the port controls the noise. Listening on a bare port only
+//! listens on the loopback interface for that reason. There is no OSC message to stop the daemon.
+
+use anyhow::anyhow;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use adh_rs::{presets::preset, protocol::GUICommand, WEIGHTS_NUM};
+
+use crate::DaemonCommand;
+
+/// A fader sends many band messages per second but every change of the weights generates new noise,
+/// so band messages that arrive within this time are applied together.
+const BAND_COALESCE: Duration = Duration::from_millis(50);
+/// The maximum size of a UDP datagram.
+const PACKET_BUF_LEN: usize = 65536;
+
+/// An OSC message with the only argument we care about, its first numeric one.
+#[derive(Debug)]
+struct Message {
+    address: String,
+    value: Option<f32>,
+}
+
+/// Listen on `address` and relay OSC messages in a background thread.
+pub fn spawn_listener(address: SocketAddr, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let socket = UdpSocket::bind(address)?;
+    println!("Listening for OSC messages on {}.", address);
+
+    thread::spawn(move || {
+        if let Err(e) = relay(socket, tx) {
+            eprintln!("OSC listener failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn relay(socket: UdpSocket, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let mut buf = vec![0; PACKET_BUF_LEN];
+    let mut bands = Vec::new();
+    loop {
+        // Only wait a short time while band changes are pending, then apply them.
+        socket.set_read_timeout((!bands.is_empty()).then_some(BAND_COALESCE))?;
+        let read_bytes = match socket.recv(&mut buf) {
+            Ok(read_bytes) => read_bytes,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                tx.send(DaemonCommand::SetBands(std::mem::take(&mut bands)))?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut messages = Vec::new();
+        if let Err(e) = parse_packet(&buf[..read_bytes], &mut messages) {
+            eprintln!("Received invalid OSC packet: {}", e);
+            continue;
+        }
+        for message in messages {
+            if let Some(band) = message.address.strip_prefix("/adh/band/") {
+                match (band.parse::<usize>(), message.value) {
+                    (Ok(band), Some(weight)) if band < WEIGHTS_NUM => bands.push((band, weight.clamp(0.0, 1.0))),
+                    _ => eprintln!("Invalid OSC band message: {:?}", message),
+                }
+                continue;
+            }
+            let Some(command) = to_command(&message) else {
+                continue;
+            };
+            // Keep the order of the messages.
+            if !bands.is_empty() {
+                tx.send(DaemonCommand::SetBands(std::mem::take(&mut bands)))?;
+            }
+            tx.send(DaemonCommand::GUI(command, None))?;
+        }
+    }
+}
+
+/// The command for a message other than a band message, None if there is nothing to do.
+fn to_command(message: &Message) -> Option<GUICommand> {
+    // Releasing a button.
+    let released = message.value == Some(0.0);
+    let command = match message.address.as_str() {
+        "/adh/volume" => match message.value {
+            Some(volume) => GUICommand::SetVolume(volume.clamp(0.0, 1.0)),
+            None => {
+                eprintln!("OSC volume message without a value");
+                return None;
+            }
+        },
+        "/adh/toggle" if !released => GUICommand::Toggle,
+        "/adh/pause" if !released => GUICommand::Pause,
+        "/adh/resume" if !released => GUICommand::Resume,
+        "/adh/toggle" | "/adh/pause" | "/adh/resume" => return None,
+        address => match address.strip_prefix("/adh/preset/").map(|name| (name, preset(name))) {
+            Some((_, Some(weights))) if !released => GUICommand::SetWeights(weights),
+            Some((_, Some(_))) => return None,
+            Some((name, None)) => {
+                eprintln!("Unknown preset in OSC message: {}", name);
+                return None;
+            }
+            None => {
+                eprintln!("Unknown OSC address: {}", address);
+                return None;
+            }
+        },
+    };
+    Some(command)
+}
+
+/// Parse a message or a bundle of messages and bundles.
+fn parse_packet(packet: &[u8], messages: &mut Vec<Message>) -> Result<(), anyhow::Error> {
+    let mut reader = Reader { buf: packet, pos: 0 };
+    if packet.starts_with(b"#bundle\0") {
+        reader.pos = 16; // The bundle header and the time tag.
+        while reader.pos < packet.len() {
+            let size = usize::try_from(reader.i32()?)?;
+            parse_packet(reader.bytes(size)?, messages)?;
+        }
+        return Ok(());
+    }
+
+    let address = reader.string()?;
+    // Very old implementations omit the type tags. We then treat the message as having no arguments.
+    let type_tags = if reader.pos < packet.len() {
+        reader.string()?
+    } else {
+        String::from(",")
+    };
+
+    let mut value = None;
+    for tag in type_tags.chars().skip(1) {
+        let argument = match tag {
+            'i' => Some(reader.i32()? as f32),
+            'f' => Some(f32::from_be_bytes(reader.array()?)),
+            'h' => Some(i64::from_be_bytes(reader.array()?) as f32),
+            'd' => Some(f64::from_be_bytes(reader.array()?) as f32),
+            'T' => Some(1.0),
+            'F' => Some(0.0),
+            's' | 'S' => {
+                reader.string()?;
+                None
+            }
+            'b' => {
+                let size = usize::try_from(reader.i32()?)?;
+                reader.bytes(size.next_multiple_of(4))?;
+                None
+            }
+            't' | 'c' | 'r' | 'm' => {
+                reader.bytes(if tag == 't' { 8 } else { 4 })?;
+                None
+            }
+            'N' | 'I' | '[' | ']' => None,
+            _ => return Err(anyhow!("Unknown OSC type tag {}", tag)),
+        };
+        value = value.or(argument);
+    }
+
+    messages.push(Message { address, value });
+    Ok(())
+}
+
+/// Reads the big-endian, 4-byte aligned data of OSC.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(anyhow!("OSC packet ends too early"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], anyhow::Error> {
+        Ok(self.bytes(N)?.try_into()?)
+    }
+
+    fn i32(&mut self) -> Result<i32, anyhow::Error> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    /// A null-terminated string that is padded to a multiple of 4 bytes.
+    fn string(&mut self) -> Result<String, anyhow::Error> {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(anyhow!("OSC string without terminating null byte"))?;
+        let string = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.bytes((len + 1).next_multiple_of(4))?;
+        Ok(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a string the way OSC does, null-terminated and padded to a multiple of 4 bytes.
+    fn osc_string(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize((s.len() + 1).next_multiple_of(4), 0);
+        bytes
+    }
+
+    fn message(address: &str, type_tags: &str, arguments: &[&[u8]]) -> Vec<u8> {
+        let mut packet = osc_string(address);
+        packet.extend(osc_string(type_tags));
+        for argument in arguments {
+            packet.extend_from_slice(argument);
+        }
+        packet
+    }
+
+    fn parse(packet: &[u8]) -> Result<Vec<Message>, anyhow::Error> {
+        let mut messages = Vec::new();
+        parse_packet(packet, &mut messages)?;
+        Ok(messages)
+    }
+
+    #[test]
+    fn strings_are_padded_to_four_bytes() {
+        // With a length that is a multiple of 4, the null byte needs 4 more bytes.
+        for (address, len) in [("/ab", 4), ("/abc", 8), ("/abcdef", 8), ("/abcdefg", 12)] {
+            let mut packet = osc_string(address);
+            assert_eq!(packet.len(), len);
+            packet.extend(osc_string(","));
+            let messages = parse(&packet).unwrap();
+            assert_eq!(messages[0].address, address);
+            assert_eq!(messages[0].value, None);
+        }
+    }
+
+    #[test]
+    fn string_without_null_byte_is_rejected() {
+        assert!(parse(b"/adh").is_err());
+    }
+
+    #[test]
+    fn numeric_type_tags() {
+        let cases: [(&str, Vec<u8>, f32); 6] = [
+            (",i", 3i32.to_be_bytes().to_vec(), 3.0),
+            (",f", 0.5f32.to_be_bytes().to_vec(), 0.5),
+            (",h", (-2i64).to_be_bytes().to_vec(), -2.0),
+            (",d", 0.25f64.to_be_bytes().to_vec(), 0.25),
+            (",T", Vec::new(), 1.0),
+            (",F", Vec::new(), 0.0),
+        ];
+        for (type_tags, argument, value) in cases {
+            let messages = parse(&message("/adh/volume", type_tags, &[&argument])).unwrap();
+            assert_eq!(messages[0].value, Some(value), "{}", type_tags);
+        }
+    }
+
+    #[test]
+    fn first_numeric_argument_wins_after_skipped_ones() {
+        let blob = [&3i32.to_be_bytes()[..], &[1, 2, 3, 0]].concat();
+        let packet = message(
+            "/adh/band/2",
+            ",sbNtf",
+            &[&osc_string("name"), &blob, &[0; 8], &0.75f32.to_be_bytes()],
+        );
+        let messages = parse(&packet).unwrap();
+        assert_eq!(messages[0].value, Some(0.75));
+    }
+
+    #[test]
+    fn missing_type_tags_mean_no_arguments() {
+        let messages = parse(&osc_string("/adh/toggle")).unwrap();
+        assert_eq!(messages[0].value, None);
+    }
+
+    #[test]
+    fn unknown_type_tag_and_short_argument_are_rejected() {
+        assert!(parse(&message("/adh/volume", ",x", &[])).is_err());
+        assert!(parse(&message("/adh/volume", ",f", &[&[0, 0]])).is_err());
+    }
+
+    #[test]
+    fn nested_bundles_are_unpacked_in_order() {
+        let first = message("/adh/band/0", ",f", &[&0.5f32.to_be_bytes()]);
+        let second = message("/adh/pause", ",", &[]);
+        let mut inner = osc_string("#bundle");
+        inner.extend([0; 8]);
+        inner.extend((second.len() as i32).to_be_bytes());
+        inner.extend(&second);
+        let mut outer = osc_string("#bundle");
+        outer.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        outer.extend((first.len() as i32).to_be_bytes());
+        outer.extend(&first);
+        outer.extend((inner.len() as i32).to_be_bytes());
+        outer.extend(&inner);
+
+        let messages = parse(&outer).unwrap();
+        let addresses: Vec<_> = messages.iter().map(|message| message.address.as_str()).collect();
+        assert_eq!(addresses, ["/adh/band/0", "/adh/pause"]);
+    }
+
+    #[test]
+    fn bundle_element_larger_than_the_bundle_is_rejected() {
+        let mut bundle = osc_string("#bundle");
+        bundle.extend([0; 8]);
+        bundle.extend(100i32.to_be_bytes());
+        assert!(parse(&bundle).is_err());
+    }
+
+    #[test]
+    fn released_buttons_are_ignored() {
+        let message = |address: &str, value| Message {
+            address: address.to_owned(),
+            value,
+        };
+        assert!(matches!(
+            to_command(&message("/adh/toggle", None)),
+            Some(GUICommand::Toggle)
+        ));
+        assert!(matches!(
+            to_command(&message("/adh/pause", Some(1.0))),
+            Some(GUICommand::Pause)
+        ));
+        assert!(to_command(&message("/adh/resume", Some(0.0))).is_none());
+        assert!(to_command(&message("/adh/preset/brown", Some(0.0))).is_none());
+        assert!(matches!(
+            to_command(&message("/adh/preset/brown", None)),
+            Some(GUICommand::SetWeights(_))
+        ));
+        assert!(matches!(
+            to_command(&message("/adh/volume", Some(2.0))),
+            Some(GUICommand::SetVolume(volume)) if volume == 1.0
+        ));
+        assert!(to_command(&message("/adh/quit", None)).is_none());
+    }
+}
@@ -0,0 +1,930 @@
+//! The player owns the audio stream and the noise that is played on it.
+//!
+//! The noise (i.e. the generator state) is kept separately from the stream, so that we can rebuild the stream
+//! on another device without changing the noise. This happens when the user chooses another output device
+//! or when the stream fails, e.g. because the device was unplugged or the sound server restarted.
+
+use anyhow::anyhow;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use xdg::BaseDirectories;
+
+use adh_rs::{
+    alarm::{self, Alarm},
+    audio_bridge::OutputDevice,
+    config::{Config, MidiMapping, MidiTarget},
+    gain::GainControl,
+    generator::gen_channel_samples,
+    presets::preset_name,
+    protocol::{Event, SleepTimerStatus, Status, StreamStatus},
+    sink::{play, AudioSink, AudioStream, ErrorHandler, SharedSamples},
+    slots::{Slots, SLOTS_NUM},
+    Weights,
+};
+
+use crate::DaemonCommand;
+
+/// How long to wait before trying to rebuild a failed stream for the first time.
+const RECOVERY_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// We double the waiting time after each failed attempt up to this maximum.
+const RECOVERY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// When quitting, how much longer than the fade we wait for the stream to become silent.
+/// The stream may lag behind a bit because of its buffer.
+const QUIT_GRACE: Duration = Duration::from_millis(200);
+
+/// How often the weights take a step towards the faders of a MIDI controller at most. Each step generates new noise.
+const TRANSITION_INTERVAL: Duration = Duration::from_millis(100);
+/// How much a weight changes at most per `TRANSITION_INTERVAL`, so moving a fader all the way takes a second.
+const TRANSITION_STEP: f32 = 0.1;
+/// Generating noise blocks the event loop, so steps are at least this many times as far apart as generating took.
+/// Slow machines then take fewer but larger steps and the glide keeps its speed.
+const TRANSITION_LOAD_FACTOR: u32 = 4;
+/// The monotonic clock stops while the system is suspended, so we look at the wall clock at least this often while
+/// an alarm is set instead of trusting a single long timeout.
+const ALARM_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Alarms that we missed by more than this, e.g. because the daemon was not running, are skipped instead of starting
+/// the noise at an unexpected time.
+const ALARM_MISSED_GRACE: Duration = Duration::from_secs(15 * 60);
+/// The volume is written to the config this long after its last change, so that dragging a slider does not rewrite
+/// the file dozens of times per second.
+const CONFIG_WRITE_DELAY: Duration = Duration::from_secs(1);
+
+struct Transition {
+    /// The weights that we glide towards.
+    target: Weights,
+    /// When to take the next step.
+    at: Instant,
+    /// The time since the last step, which decides how large the next step is.
+    interval: Duration,
+}
+
+struct SleepTimer {
+    /// When to start fading out.
+    fade_start: Instant,
+    /// When the noise is silent and the timer ends.
+    end: Instant,
+    fading: bool,
+}
+
+struct Recovery {
+    at: Instant,
+    backoff: Duration,
+}
+
+pub struct Player {
+    config: Config,
+    xdg: BaseDirectories,
+    /// Declared before the sink so that it is dropped first: a stream may still write to the sink until it stops.
+    audio_stream: Option<AudioStream>,
+    /// Where the noise is played.
+    sink: Box<dyn AudioSink>,
+    /// Used to send errors of the audio stream back into the daemon's event loop.
+    tx: mpsc::Sender<DaemonCommand>,
+    /// Controls the volume of all streams.
+    gain: GainControl,
+    /// The noise that is currently played. Survives losing the stream.
+    samples: Option<SharedSamples>,
+    /// The weights of the noise that is currently played.
+    weights: Option<Weights>,
+    /// Incremented for each new stream so that we can ignore errors of streams that we already replaced.
+    stream_id: u64,
+    /// Set if the stream failed and we could not rebuild it yet.
+    recovery: Option<Recovery>,
+    /// Whether we reported a failed stream that was not rebuilt yet, see `Event::StreamLost`.
+    stream_lost: bool,
+    /// Events that can't be derived from the status, collected for the subscribers until `take_events`.
+    events: Vec<Event>,
+    /// Set while the weights glide towards new values, see `glide_bands`.
+    transition: Option<Transition>,
+    /// The target for the next MIDI controller that is moved, see `learn_midi`.
+    midi_learn: Option<MidiTarget>,
+    sleep_timer: Option<SleepTimer>,
+    /// When to write the config with a changed volume, see `CONFIG_WRITE_DELAY`.
+    config_write: Option<Instant>,
+    started: Instant,
+}
+
+impl Player {
+    pub fn new(
+        config: Config,
+        xdg: BaseDirectories,
+        sink: Box<dyn AudioSink>,
+        tx: mpsc::Sender<DaemonCommand>,
+    ) -> Self {
+        let gain = GainControl::new(config.volume);
+
+        Self {
+            config,
+            xdg,
+            audio_stream: None,
+            sink,
+            tx,
+            gain,
+            samples: None,
+            weights: None,
+            stream_id: 0,
+            recovery: None,
+            stream_lost: false,
+            events: Vec::new(),
+            transition: None,
+            midi_learn: None,
+            sleep_timer: None,
+            config_write: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Error callback for a new stream with id `stream_id` that reports errors back to the event loop.
+    fn error_handler(&self, stream_id: u64) -> ErrorHandler {
+        let tx = self.tx.clone();
+
+        Arc::new(move |error| {
+            tx.send(DaemonCommand::StreamError { stream_id, error }).ok();
+        })
+    }
+
+    /// Generate some noise chunks for each noise source which are then continuously played, blending between them.
+    /// If there is already a stream, we just swap in the new noise. Otherwise we create a new stream.
+    pub fn set_weights(&mut self, weights: Weights) -> Result<(), anyhow::Error> {
+        self.play_weights(weights, self.config.fade_duration())
+    }
+
+    /// Like `set_weights` but fade in over `fade` if the noise is paused.
+    fn play_weights(&mut self, weights: Weights, fade: Duration) -> Result<(), anyhow::Error> {
+        // New weights win over a transition that is still going on.
+        self.transition = None;
+        self.swap_weights(weights)?;
+        self.fade_in(fade);
+        Ok(())
+    }
+
+    /// Play new weights without touching the gain or the sleep timer, so that editing the weights does not resume
+    /// paused noise. Without a stream we create one, which stays silent until the noise is resumed.
+    fn swap_weights(&mut self, weights: Weights) -> Result<(), anyhow::Error> {
+        let config = &self.config;
+        let make_samples = |channels| gen_channel_samples(&weights, &config.channel_map, config.mono_downmix, channels);
+
+        if let Some(audio_stream) = &self.audio_stream {
+            let new_samples = make_samples(audio_stream.info.channels)?;
+            *audio_stream.samples.lock().unwrap() = new_samples;
+            self.weights = Some(weights);
+            return Ok(());
+        }
+
+        let stream_id = self.stream_id + 1;
+        let on_error = self.error_handler(stream_id);
+        let device = self.config.output_device.as_ref();
+
+        let new_audio_stream = play(self.sink.as_ref(), device, &make_samples, self.gain.clone(), on_error)?;
+        self.samples = Some(new_audio_stream.samples.clone());
+        self.weights = Some(weights);
+        self.start_stream(stream_id, new_audio_stream);
+        Ok(())
+    }
+
+    /// Change single bands of the current weights, or of white noise if there are none yet.
+    /// Unlike `set_weights`, this does not resume paused noise.
+    pub fn set_bands(&mut self, bands: &[(usize, f32)]) -> Result<(), anyhow::Error> {
+        let mut weights = self.weights.unwrap_or_default();
+        for &(band, weight) in bands {
+            *weights.v.get_mut(band).ok_or(anyhow!("There is no band {}", band))? = weight;
+        }
+        self.transition = None;
+        self.swap_weights(weights)
+    }
+
+    /// Let single bands glide towards new weights instead of jumping, e.g. for the faders of a MIDI controller.
+    /// The steps are taken in `on_deadline`, which also limits how often new noise is generated.
+    pub fn glide_bands(&mut self, bands: &[(usize, f32)]) {
+        let (at, interval) = self
+            .transition
+            .as_ref()
+            .map_or((Instant::now(), TRANSITION_INTERVAL), |transition| {
+                (transition.at, transition.interval)
+            });
+        let mut target = self
+            .transition
+            .as_ref()
+            .map(|transition| transition.target)
+            .or(self.weights)
+            .unwrap_or_default();
+        for &(band, weight) in bands {
+            if let Some(w) = target.v.get_mut(band) {
+                *w = weight;
+            }
+        }
+        self.transition = Some(Transition { target, at, interval });
+    }
+
+    /// Map the next MIDI controller that is moved to `target`.
+    pub fn learn_midi(&mut self, target: MidiTarget) {
+        println!("Move a controller to map it to {:?}.", target);
+        self.midi_learn = Some(target);
+    }
+
+    /// A controller on a MIDI channel was moved to `value` in 0..128.
+    pub fn on_midi_control(&mut self, channel: u8, controller: u32, value: i32) {
+        if let Some(target) = self.midi_learn.take() {
+            // Every controller changes one target and every target is changed by one controller.
+            self.config.midi_mappings.retain(|mapping| {
+                mapping.target != target && (mapping.channel, mapping.controller) != (channel, controller)
+            });
+            self.config.midi_mappings.push(MidiMapping {
+                channel,
+                controller,
+                target,
+            });
+            self.config.write_to_disk(&self.xdg);
+            println!(
+                "Mapped controller {} on MIDI channel {} to {:?}.",
+                controller, channel, target
+            );
+            return;
+        }
+
+        let Some(mapping) = self
+            .config
+            .midi_mappings
+            .iter()
+            .find(|mapping| mapping.channel == channel && mapping.controller == controller)
+        else {
+            return;
+        };
+        let value = (value as f32 / 127.0).clamp(0.0, 1.0);
+        match mapping.target {
+            MidiTarget::Band(band) => self.glide_bands(&[(band, value)]),
+            // The gain already ramps to a new volume.
+            MidiTarget::Volume => self.set_volume(value),
+        }
+    }
+
+    /// Remember the chosen device and move the noise that is currently playing over to it.
+    /// If that fails, we keep trying in the background.
+    pub fn set_output_device(&mut self, device: Option<OutputDevice>) -> Result<(), anyhow::Error> {
+        self.config.output_device = device;
+        self.config.write_to_disk(&self.xdg);
+
+        self.rebuild_stream().inspect_err(|e| {
+            self.lose_stream(e.to_string());
+            self.schedule_recovery(RECOVERY_BACKOFF_MIN);
+        })
+    }
+
+    /// The gain stage of the stream ramps to the new volume.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.gain.set_volume(volume);
+        self.config.volume = self.gain.volume();
+        self.config_write = Some(Instant::now() + CONFIG_WRITE_DELAY);
+    }
+
+    /// Write the config now if a change is still waiting to be written.
+    fn flush_config(&mut self) {
+        if self.config_write.take().is_some() {
+            self.config.write_to_disk(&self.xdg);
+        }
+    }
+
+    /// Pausing and resuming fades the noise out and in.
+    pub fn toggle(&mut self) {
+        if self.gain.is_paused() {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.gain.pause(self.config.fade_duration());
+    }
+
+    /// Resuming only makes sense if we already have some noise.
+    pub fn resume(&mut self) {
+        if self.samples.is_some() {
+            self.fade_in(self.config.fade_duration());
+        }
+    }
+
+    /// Pause after `duration`, fading out during the last `fade` of it.
+    pub fn set_sleep_timer(&mut self, duration: Duration, fade: Duration) -> Result<(), anyhow::Error> {
+        let end = Instant::now()
+            .checked_add(duration)
+            .ok_or(anyhow!("A sleep timer of {:?} is too long", duration))?;
+        self.sleep_timer = Some(SleepTimer {
+            fade_start: end - fade.min(duration),
+            end,
+            fading: false,
+        });
+        println!("The sleep timer pauses the noise in {:?}.", duration);
+        Ok(())
+    }
+
+    /// Cancel the sleep timer. If it is already fading out, the noise comes back.
+    pub fn cancel_sleep_timer(&mut self) {
+        if self.sleep_timer.take().is_some_and(|sleep_timer| sleep_timer.fading) {
+            self.resume();
+        }
+    }
+
+    /// Fade in to the volume over `fade`. Wanting to hear the noise again wins over a sleep timer that is fading it
+    /// out.
+    fn fade_in(&mut self, fade: Duration) {
+        if self.sleep_timer.as_ref().is_some_and(|sleep_timer| sleep_timer.fading) {
+            self.sleep_timer = None;
+        }
+        self.gain.resume(fade);
+    }
+
+    /// Set a new alarm. It gets the next free id.
+    pub fn set_alarm(&mut self, mut alarm: Alarm) -> Result<(), anyhow::Error> {
+        if alarm.slot >= SLOTS_NUM {
+            return Err(anyhow!("There is no slot {}", alarm.slot));
+        }
+        if !alarm.ramp_secs.is_finite() || alarm.ramp_secs < 0.0 {
+            return Err(anyhow!("Invalid ramp of {} seconds", alarm.ramp_secs));
+        }
+        // Daily alarms in the past ring next on the following day, but a one-off alarm would never ring.
+        if !alarm.daily && alarm.time() <= SystemTime::now() {
+            return Err(anyhow!("The alarm at {} is in the past", alarm.local_time_string()));
+        }
+        alarm.id = self.config.alarms.iter().map(|alarm| alarm.id + 1).max().unwrap_or(1);
+        println!(
+            "Alarm {} is set to play slot {} at {}.",
+            alarm.id,
+            alarm.slot,
+            alarm.local_time_string()
+        );
+        self.config.alarms.push(alarm);
+        self.config.alarms.sort_by_key(|alarm| alarm.at);
+        self.config.write_to_disk(&self.xdg);
+        Ok(())
+    }
+
+    pub fn alarms(&self) -> Vec<Alarm> {
+        self.config.alarms.clone()
+    }
+
+    pub fn cancel_alarm(&mut self, id: u32) -> Result<(), anyhow::Error> {
+        let len = self.config.alarms.len();
+        self.config.alarms.retain(|alarm| alarm.id != id);
+        if self.config.alarms.len() == len {
+            return Err(anyhow!("There is no alarm {}", id));
+        }
+        self.config.write_to_disk(&self.xdg);
+        Ok(())
+    }
+
+    /// Fade out and wait until the stream is silent, so that quitting does not cut off the noise.
+    /// Then stop the stream and finish the sink, e.g. the header of a WAV file.
+    pub fn quit(&mut self) {
+        self.flush_config();
+        if self.audio_stream.is_some() {
+            let fade = self.config.fade_duration();
+            self.gain.pause(fade);
+            let deadline = Instant::now() + fade + QUIT_GRACE;
+            while self.gain.fade_level() > 0.0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        self.audio_stream = None;
+        if let Err(e) = self.sink.finish() {
+            eprintln!("Finishing the output failed: {}", e);
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            playing: self.audio_stream.is_some() && !self.gain.is_paused(),
+            weights: self.weights,
+            volume: self.gain.volume(),
+            output_device: self.config.output_device.clone(),
+            stream: self.audio_stream.as_ref().map(|audio_stream| StreamStatus {
+                description: audio_stream.info.description.clone(),
+                sample_rate: audio_stream.info.sample_rate,
+                channels: audio_stream.info.channels,
+                buffer_frames: audio_stream.info.buffer_frames,
+                stats: audio_stream.stats(),
+            }),
+            uptime_secs: self.started.elapsed().as_secs_f64(),
+            preset: self.weights.as_ref().and_then(preset_name).map(str::to_owned),
+            sleep_timer: self.sleep_timer.as_ref().map(|sleep_timer| SleepTimerStatus {
+                remaining_secs: sleep_timer.end.saturating_duration_since(Instant::now()).as_secs_f32(),
+                fade_secs: (sleep_timer.end - sleep_timer.fade_start).as_secs_f32(),
+                fading: sleep_timer.fading,
+            }),
+        }
+    }
+
+    /// The stream failed, so we drop it and try to play the same noise on a new stream.
+    pub fn on_stream_error(&mut self, stream_id: u64, error: String) {
+        if stream_id != self.stream_id || self.audio_stream.is_none() {
+            // Errors of streams that were already replaced, or follow-up errors of the same stream.
+            return;
+        }
+
+        eprintln!("Audio stream failed: {}. Trying to rebuild it.", error);
+        self.audio_stream = None;
+        self.lose_stream(error);
+        if let Err(e) = self.rebuild_stream() {
+            eprintln!("{}", e);
+            self.schedule_recovery(RECOVERY_BACKOFF_MIN);
+        }
+    }
+
+    /// The events since the last call that the status does not show, e.g. a lost stream.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// The next point in time when the event loop should call `on_deadline`.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let recovery = self.recovery.as_ref().map(|recovery| recovery.at);
+        let transition = self.transition.as_ref().map(|transition| transition.at);
+        let sleep_timer = self.sleep_timer.as_ref().map(|sleep_timer| match sleep_timer.fading {
+            false => sleep_timer.fade_start,
+            true => sleep_timer.end,
+        });
+        let alarm = self.config.alarms.first().map(|alarm| {
+            let until = alarm.time().duration_since(SystemTime::now()).unwrap_or_default();
+            Instant::now() + until.min(ALARM_CHECK_INTERVAL)
+        });
+        recovery
+            .into_iter()
+            .chain(transition)
+            .chain(sleep_timer)
+            .chain(alarm)
+            .chain(self.config_write)
+            .min()
+    }
+
+    pub fn on_deadline(&mut self) {
+        self.step_transition();
+        self.step_sleep_timer();
+        self.step_alarms();
+        if self.config_write.is_some_and(|at| at <= Instant::now()) {
+            self.flush_config();
+        }
+
+        let Some(recovery) = self.recovery.take() else {
+            return;
+        };
+        if recovery.at > Instant::now() {
+            self.recovery = Some(recovery);
+            return;
+        }
+
+        if let Err(e) = self.rebuild_stream() {
+            eprintln!("{}", e);
+            self.schedule_recovery((recovery.backoff * 2).min(RECOVERY_BACKOFF_MAX));
+        }
+    }
+
+    /// Start the long fade out of the sleep timer or end it, if it is time for that.
+    fn step_sleep_timer(&mut self) {
+        let Some(sleep_timer) = &mut self.sleep_timer else {
+            return;
+        };
+        let now = Instant::now();
+        if !sleep_timer.fading && now >= sleep_timer.fade_start {
+            let fade = sleep_timer.end.saturating_duration_since(now);
+            println!("The sleep timer fades out the noise over {:?}.", fade);
+            sleep_timer.fading = true;
+            self.gain.pause(fade);
+        } else if sleep_timer.fading && now >= sleep_timer.end {
+            println!("The sleep timer ended.");
+            self.sleep_timer = None;
+        }
+    }
+
+    /// Ring the alarms that are due and schedule the next time of daily alarms.
+    fn step_alarms(&mut self) {
+        let now = SystemTime::now();
+        let (due, mut alarms): (Vec<_>, Vec<_>) = std::mem::take(&mut self.config.alarms)
+            .into_iter()
+            .partition(|alarm| alarm.time() <= now);
+        if due.is_empty() {
+            self.config.alarms = alarms;
+            return;
+        }
+
+        for alarm in due {
+            let late = now.duration_since(alarm.time()).unwrap_or_default();
+            if late > ALARM_MISSED_GRACE {
+                println!(
+                    "Skipped alarm {} of {}, it is {} minutes late.",
+                    alarm.id,
+                    alarm.local_time_string(),
+                    late.as_secs() / 60
+                );
+            } else if let Err(e) = self.ring(&alarm) {
+                eprintln!("Alarm {} failed: {}", alarm.id, e);
+            }
+
+            // A daily alarm that was missed for days rings next at its time after now.
+            let mut next = alarm.next();
+            while let Some(at) = next.filter(|at| *at <= alarm::unix_secs(now)) {
+                next = Alarm { at, ..alarm.clone() }.next();
+            }
+            if let Some(at) = next {
+                alarms.push(Alarm { at, ..alarm });
+            }
+        }
+        alarms.sort_by_key(|alarm| alarm.at);
+        self.config.alarms = alarms;
+        self.config.write_to_disk(&self.xdg);
+    }
+
+    /// Play the slot of the alarm, fading in from silence over its ramp. An alarm also ends a sleep timer.
+    fn ring(&mut self, alarm: &Alarm) -> Result<(), anyhow::Error> {
+        println!(
+            "Alarm {} plays slot {}, fading in over {:?}.",
+            alarm.id,
+            alarm.slot,
+            alarm.ramp()
+        );
+        self.sleep_timer = None;
+        let weights = Slots::load_from_disk(&self.xdg).recall_slot(alarm.slot);
+        self.play_weights(weights, alarm.ramp())
+    }
+
+    /// Move the weights one step towards the target of the transition, if it is time for that.
+    fn step_transition(&mut self) {
+        let Some(transition) = self.transition.take() else {
+            return;
+        };
+        if transition.at > Instant::now() {
+            self.transition = Some(transition);
+            return;
+        }
+
+        let step = TRANSITION_STEP * transition.interval.as_secs_f32() / TRANSITION_INTERVAL.as_secs_f32();
+        let mut weights = self.weights.unwrap_or_default();
+        for (w, target) in weights.v.iter_mut().zip(transition.target.v) {
+            *w = if (target - *w).abs() <= step {
+                target
+            } else {
+                *w + step.copysign(target - *w)
+            };
+        }
+        let started = Instant::now();
+        if let Err(e) = self.swap_weights(weights) {
+            eprintln!("{}", e);
+            return;
+        }
+        if weights != transition.target {
+            let interval = TRANSITION_INTERVAL.max(started.elapsed() * TRANSITION_LOAD_FACTOR);
+            self.transition = Some(Transition {
+                target: transition.target,
+                at: Instant::now() + interval,
+                interval,
+            });
+        }
+    }
+
+    fn schedule_recovery(&mut self, backoff: Duration) {
+        println!(
+            "Trying again to rebuild the audio stream in {} seconds.",
+            backoff.as_secs()
+        );
+        self.recovery = Some(Recovery {
+            at: Instant::now() + backoff,
+            backoff,
+        });
+    }
+
+    /// Play the current noise on a new stream.
+    /// The paused state is kept because it is part of the gain control and not the stream.
+    /// If the new stream has another number of channels, e.g. on another device, the noise is generated again.
+    fn rebuild_stream(&mut self) -> Result<(), anyhow::Error> {
+        let Some((samples, weights)) = self.samples.clone().zip(self.weights) else {
+            return Ok(());
+        };
+
+        // Drop the old stream first so that we don't play on two devices at once.
+        self.audio_stream = None;
+        let stream_id = self.stream_id + 1;
+        let on_error = self.error_handler(stream_id);
+        let config = &self.config;
+        let make_samples = |channels| gen_channel_samples(&weights, &config.channel_map, config.mono_downmix, channels);
+        let new_audio_stream = self.sink.play_samples(
+            config.output_device.as_ref(),
+            samples,
+            &make_samples,
+            self.gain.clone(),
+            on_error,
+        )?;
+        self.start_stream(stream_id, new_audio_stream);
+        Ok(())
+    }
+
+    /// Keep playing on a stream that was just started.
+    fn start_stream(&mut self, stream_id: u64, audio_stream: AudioStream) {
+        log_stream_start(&audio_stream);
+        self.stream_id = stream_id;
+        self.audio_stream = Some(audio_stream);
+        self.recovery = None;
+        if self.stream_lost {
+            self.stream_lost = false;
+            self.events.push(Event::StreamRecovered);
+        }
+    }
+
+    fn lose_stream(&mut self, error: String) {
+        self.stream_lost = true;
+        self.events.push(Event::StreamLost(error));
+    }
+}
+
+fn log_stream_start(audio_stream: &AudioStream) {
+    let info = &audio_stream.info;
+    match info.buffer_frames {
+        Some(frames) => println!(
+            "Started stream on {} with a buffer of {} frames ({:.1} ms).",
+            info.description,
+            frames,
+            frames as f32 * 1000.0 / info.sample_rate as f32
+        ),
+        None => println!("Started stream on {} with the default buffer size.", info.description),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use adh_rs::{
+        presets::preset,
+        sink::{BufferRequest, NullSink, SinkKind},
+        wav::read_wav,
+        WEIGHTS_NUM,
+    };
+    use std::{fs::File, path::PathBuf};
+
+    /// A player on `sink` without fades, which writes its config into a temporary directory instead of the one of
+    /// the user.
+    fn player(sink: Box<dyn AudioSink>) -> Player {
+        let config = Config {
+            fade_secs: 0.0,
+            ..Config::default()
+        };
+        let mut xdg = BaseDirectories::with_prefix("adh-rs");
+        xdg.config_home = Some(std::env::temp_dir().join(format!("adh-rs-test-{}", std::process::id())));
+        let (tx, _) = mpsc::channel();
+        Player::new(config, xdg, sink, tx)
+    }
+
+    fn null_player() -> Player {
+        player(Box::new(NullSink::new(BufferRequest::default())))
+    }
+
+    #[test]
+    fn weights_start_playing() {
+        let mut player = null_player();
+        assert!(!player.status().playing);
+
+        player.set_weights(preset("brown").unwrap()).unwrap();
+        let status = player.status();
+        assert!(status.playing);
+        assert_eq!(status.preset.as_deref(), Some("brown"));
+        assert_eq!(status.stream.unwrap().description, "null");
+    }
+
+    #[test]
+    fn bands_do_not_resume_paused_noise() {
+        let mut player = null_player();
+        player.set_weights(preset("white").unwrap()).unwrap();
+        player.pause();
+
+        player.set_bands(&[(0, 0.5)]).unwrap();
+        let status = player.status();
+        assert!(!status.playing);
+        assert_eq!(status.weights.unwrap().v[0], 0.5);
+        assert!(player.set_bands(&[(WEIGHTS_NUM, 0.5)]).is_err());
+    }
+
+    #[test]
+    fn sleep_timer_pauses() {
+        let mut player = null_player();
+        player.set_weights(preset("pink").unwrap()).unwrap();
+        player
+            .set_sleep_timer(Duration::from_millis(50), Duration::from_millis(20))
+            .unwrap();
+        assert!(player.status().sleep_timer.is_some());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while player.status().sleep_timer.is_some() && Instant::now() < deadline {
+            thread::sleep(
+                player
+                    .next_deadline()
+                    .unwrap()
+                    .saturating_duration_since(Instant::now()),
+            );
+            player.on_deadline();
+        }
+        let status = player.status();
+        assert!(status.sleep_timer.is_none());
+        assert!(!status.playing);
+    }
+
+    #[test]
+    fn too_long_sleep_timer_is_refused() {
+        let mut player = null_player();
+        // Accepted by `Duration::try_from_secs_f32`, but way beyond what an `Instant` can hold.
+        let duration = Duration::try_from_secs_f32(1e19).unwrap();
+        assert!(player.set_sleep_timer(duration, Duration::ZERO).is_err());
+        assert!(player.status().sleep_timer.is_none());
+    }
+
+    fn alarm(at: SystemTime, daily: bool) -> Alarm {
+        Alarm {
+            id: 0,
+            at: alarm::unix_secs(at),
+            daily,
+            slot: 0,
+            ramp_secs: 0.0,
+        }
+    }
+
+    #[test]
+    fn past_one_off_alarms_are_refused() {
+        let mut player = null_player();
+        let now = SystemTime::now();
+        assert!(player.set_alarm(alarm(now - Duration::from_secs(60), false)).is_err());
+        assert!(player.set_alarm(alarm(now, false)).is_err());
+        assert!(player.alarms().is_empty());
+
+        player.set_alarm(alarm(now + Duration::from_secs(60), false)).unwrap();
+        player.set_alarm(alarm(now - Duration::from_secs(60), true)).unwrap();
+        let alarms = player.alarms();
+        assert_eq!(alarms.len(), 2);
+        assert_eq!(alarms.iter().map(|alarm| alarm.id).collect::<Vec<_>>(), [2, 1]);
+    }
+
+    #[test]
+    fn invalid_alarms_are_refused() {
+        let mut player = null_player();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(player
+            .set_alarm(Alarm {
+                slot: SLOTS_NUM,
+                ..alarm(later, false)
+            })
+            .is_err());
+        assert!(player
+            .set_alarm(Alarm {
+                ramp_secs: f32::NAN,
+                ..alarm(later, false)
+            })
+            .is_err());
+        assert!(player
+            .set_alarm(Alarm {
+                ramp_secs: -1.0,
+                ..alarm(later, false)
+            })
+            .is_err());
+        assert!(player.alarms().is_empty());
+    }
+
+    #[test]
+    fn resuming_cancels_a_fading_sleep_timer() {
+        let mut player = null_player();
+        player.set_weights(preset("pink").unwrap()).unwrap();
+        player
+            .set_sleep_timer(Duration::from_secs(60), Duration::from_secs(60))
+            .unwrap();
+        player.on_deadline();
+        assert!(player.status().sleep_timer.unwrap().fading);
+
+        player.resume();
+        let status = player.status();
+        assert!(status.sleep_timer.is_none());
+        assert!(status.playing);
+    }
+
+    #[test]
+    fn learning_maps_the_next_controller() {
+        let mut player = null_player();
+        player.learn_midi(MidiTarget::Band(3));
+        player.on_midi_control(0, 7, 100);
+        // Learning does not change the target yet.
+        assert!(player.transition.is_none());
+        assert_eq!(
+            player.config.midi_mappings,
+            [MidiMapping {
+                channel: 0,
+                controller: 7,
+                target: MidiTarget::Band(3)
+            }]
+        );
+
+        // A controller only changes one target.
+        player.learn_midi(MidiTarget::Volume);
+        player.on_midi_control(0, 7, 0);
+        assert_eq!(
+            player.config.midi_mappings,
+            [MidiMapping {
+                channel: 0,
+                controller: 7,
+                target: MidiTarget::Volume
+            }]
+        );
+    }
+
+    #[test]
+    fn controllers_set_the_volume() {
+        let mut player = null_player();
+        player.learn_midi(MidiTarget::Volume);
+        player.on_midi_control(2, 7, 0);
+
+        for (value, volume) in [(127, 1.0), (0, 0.0), (200, 1.0), (-5, 0.0)] {
+            player.on_midi_control(2, 7, value);
+            assert_eq!(player.status().volume, volume, "value {}", value);
+        }
+        player.on_midi_control(2, 7, 64);
+        assert!((player.status().volume - 64.0 / 127.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unmapped_controllers_are_ignored() {
+        let mut player = null_player();
+        player.learn_midi(MidiTarget::Volume);
+        player.on_midi_control(0, 7, 0);
+        player.on_midi_control(0, 7, 127);
+
+        player.on_midi_control(1, 7, 0);
+        player.on_midi_control(0, 8, 0);
+        assert_eq!(player.status().volume, 1.0);
+        assert!(player.transition.is_none());
+    }
+
+    #[test]
+    fn bands_glide_to_the_controller() {
+        let mut player = null_player();
+        player.set_weights(preset("white").unwrap()).unwrap();
+        player.learn_midi(MidiTarget::Band(0));
+        player.on_midi_control(0, 0, 0);
+
+        player.on_midi_control(0, 0, 0);
+        let mut weight = player.status().weights.unwrap().v[0];
+        assert_eq!(weight, 1.0);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while player.transition.is_some() && Instant::now() < deadline {
+            thread::sleep(
+                player
+                    .next_deadline()
+                    .unwrap()
+                    .saturating_duration_since(Instant::now()),
+            );
+            player.on_deadline();
+            let next = player.status().weights.unwrap().v[0];
+            // Each step moves towards the target, but not all the way at once.
+            assert!(
+                next < weight && weight - next <= TRANSITION_STEP * 10.0,
+                "{} -> {}",
+                weight,
+                next
+            );
+            weight = next;
+        }
+        assert_eq!(weight, 0.0);
+        assert!(player.transition.is_none());
+        // The other bands stay where they were.
+        assert_eq!(player.status().weights.unwrap().v[1], preset("white").unwrap().v[1]);
+    }
+
+    #[test]
+    fn failed_stream_is_rebuilt() {
+        let mut player = null_player();
+        player.set_weights(preset("brown").unwrap()).unwrap();
+        player.take_events();
+
+        player.on_stream_error(player.stream_id, String::from("Device unplugged"));
+        let events = player.take_events();
+        assert!(matches!(
+            events.as_slice(),
+            [Event::StreamLost(error), Event::StreamRecovered] if error == "Device unplugged"
+        ));
+        assert!(player.status().playing);
+
+        // Errors of the replaced stream are ignored.
+        player.on_stream_error(player.stream_id - 1, String::from("Device unplugged"));
+        assert!(player.take_events().is_empty());
+    }
+
+    #[test]
+    fn quitting_completes_the_wav_file() {
+        let path = std::env::temp_dir().join(format!("adh-rs-player-test-{}.wav", std::process::id()));
+        let sink = SinkKind::Wav(PathBuf::from(&path)).open(&Config::default()).unwrap();
+        let mut player = player(sink);
+        player.set_weights(preset("brown").unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        player.quit();
+
+        // The file is complete while the player, and with it the sink, still exists.
+        let wav = read_wav(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(wav.channels, 2);
+        assert!(!wav.samples.is_empty());
+        drop(player);
+    }
+}
@@ -0,0 +1,79 @@
+//! The stream socket for clients that send or receive messages too large for a datagram.
+//! It speaks the same messages as the datagram socket, see `StreamProtocol` for the framing.
+
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::thread;
+
+use adh_rs::protocol::{Reply, StreamProtocol};
+
+use crate::{
+    client::{self, Client},
+    DaemonCommand,
+};
+
+/// Accept connections on `listener` in a background thread.
+pub fn spawn_listener(listener: UnixListener, tx: mpsc::Sender<DaemonCommand>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let uid = client::peer_uid(&stream).ok();
+                    if !client::is_same_user(uid) {
+                        eprintln!(
+                            "Rejected stream connection from user {:?}, only user {} may control the daemon.",
+                            uid,
+                            client::own_uid()
+                        );
+                        continue;
+                    }
+                    let tx = tx.clone();
+                    let id = Client::next_id();
+                    println!("Accepted stream connection {} from user {}.", id, client::own_uid());
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(id, stream, tx) {
+                            eprintln!("Stream connection {} failed: {}", id, e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept stream connection: {}", e),
+            }
+        }
+    });
+}
+
+/// Relay the commands of one connection into the event loop, like `json_socket::handle_connection`.
+fn handle_connection(id: u64, stream: UnixStream, tx: mpsc::Sender<DaemonCommand>) -> Result<(), anyhow::Error> {
+    let (replies, replies_rx) = mpsc::channel();
+    let writer = StreamProtocol::new_raw(stream.try_clone()?);
+    thread::spawn(move || write_replies(writer, replies_rx));
+
+    let protocol = StreamProtocol::new_raw(stream.try_clone()?);
+    while let Some(command) = protocol.recv()? {
+        match command {
+            Ok(command) => {
+                let client = Client::Connection {
+                    id,
+                    replies: replies.clone(),
+                };
+                tx.send(DaemonCommand::GUI(command, Some(client)))?;
+            }
+            // Tell the client that we did not understand it instead of guessing.
+            Err(e) => replies.send(Reply::Error(e.to_string()))?,
+        }
+    }
+
+    // The client closed the connection. Shutting down makes the writer stop at the next reply.
+    stream.shutdown(Shutdown::Both).ok();
+    Ok(())
+}
+
+fn write_replies(writer: StreamProtocol, replies: mpsc::Receiver<Reply>) {
+    for reply in replies {
+        if writer.reply(&reply).is_err() {
+            // Dropping the receiver makes sending to this client fail, so the event loop forgets it.
+            return;
+        }
+    }
+}
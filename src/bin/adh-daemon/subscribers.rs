@@ -0,0 +1,43 @@
+//! Clients that subscribed to changes of the daemon's state.
+//!
+//! After every command the event loop compares the state of the player before and after and pushes the
+//! differences to all subscribers. So it does not matter which client (or a failing stream) caused a change.
+//! We don't notice when a client exits, so clients are dropped once sending to them fails.
+
+use adh_rs::protocol::{Event, Protocol, Reply};
+
+use crate::client::Client;
+
+#[derive(Default)]
+pub struct Subscribers {
+    clients: Vec<Client>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self, client: &Client) {
+        if !self.clients.iter().any(|c| c.is_same(client)) {
+            self.clients.push(client.clone());
+        }
+    }
+
+    pub fn unsubscribe(&mut self, client: &Client) {
+        self.clients.retain(|c| !c.is_same(client));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn notify(&mut self, protocol: &Protocol, events: &[Event]) {
+        for event in events {
+            let reply = Reply::Event(event.clone());
+            self.clients.retain(|client| match client.send(protocol, &reply) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("Dropping subscriber {}: {}", client, e);
+                    false
+                }
+            });
+        }
+    }
+}
@@ -1,22 +1,47 @@
 use equalizer::canvas_size;
+use iced::futures::{channel::oneshot, Stream};
 use iced::keyboard::{self, Key};
-use iced::widget::column;
+use iced::widget::{column, pick_list, row, slider, text};
 use iced::window::{self, Position};
 use iced::{event, theme, Alignment, Element, Event, Point, Settings, Subscription, Task};
 use iced_runtime::core::event::Status;
 // use iced_runtime::window;
 // use iced_runtime::core::keyboard::KeyCode;
 use lerp::Lerp;
+use std::fmt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::usize;
 use xdg::{self, BaseDirectories};
 
-use adh_rs::{protocol, protocol::Protocol, slots::Slots, Weights, SEGMENTS_WEIGHT_MAX, WEIGHTS_NUM};
+use adh_rs::{
+    audio_bridge::OutputDevice,
+    config::Config,
+    is_development,
+    protocol::{self, Event as DaemonEvent, Protocol, Reply},
+    slots::Slots,
+    Weights, SEGMENTS_WEIGHT_MAX, WEIGHTS_NUM,
+};
 
 const SEGMENTS_WIDTH: f32 = 10.0;
 const CANVAS_PADDING: f32 = 20.0;
 const WEIGHTS_PADDING_Y: f32 = 20.0;
 const CANVAS_HEIGHT: f32 = 200.0;
 const SCREEN_PADDING: u32 = 20;
+/// How much the volume changes when pressing '+' or '-'.
+const VOLUME_STEP: f32 = 0.05;
+/// The durations of the sleep timer to choose from, in minutes.
+const SLEEP_TIMER_MINUTES: [u32; 6] = [15, 30, 45, 60, 90, 120];
+/// How long the sleep timer fades out at its end.
+const SLEEP_TIMER_FADE_SECS: f32 = 300.0;
+/// How long we wait for a daemon that we started to create its socket.
+const DAEMON_STARTUP_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often the event thread checks that the daemon is still there.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
 
 pub fn main() -> iced::Result {
     let (width, height) = canvas_size();
@@ -55,6 +80,17 @@ pub fn main() -> iced::Result {
     // })
 }
 
+/// The local time of day as HH:MM.
+fn clock_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    format!("{:02}:{:02}", tm.tm_hour, tm.tm_min)
+}
+
 /// This application is meant to be used as a small floating window.
 /// It renders something like an equalizer to give weights to different frequency bands.
 /// Based on the weights, colored noise is generated.
@@ -62,29 +98,150 @@ pub fn main() -> iced::Result {
 struct TrayUtility {
     equalizer: equalizer::State,
     weights: Weights,
-    protocol: Protocol,
+    /// The socket to send commands to the daemon, None until we are connected.
+    protocol: Option<Arc<Protocol>>,
+    /// Whether a connection to the daemon is being made in the background.
+    connecting: bool,
+    /// The last command that could not be sent while we were not connected. It is sent once we are.
+    pending: Option<protocol::GUICommand>,
     slots: Slots,
     xdg: BaseDirectories,
     last_segment_weight: Option<(usize, f32)>,
+    /// The output device chosen by the user, None means the default device.
+    output_device: Option<OutputDevice>,
+    volume: f32,
+    /// Whether the daemon plays noise right now. None if we don't know.
+    playing: Option<bool>,
+    /// Whether we can reach the daemon.
+    connected: bool,
+    /// When the sleep timer pauses the noise and whether it is fading out already.
+    sleep_timer: Option<(SystemTime, bool)>,
+    /// Whether the daemon lost its audio stream and could not rebuild it yet.
+    stream_lost: bool,
+}
+
+/// An entry of the sleep timer menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SleepTimerChoice {
+    Off,
+    Minutes(u32),
+}
+
+impl fmt::Display for SleepTimerChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SleepTimerChoice::Off => write!(f, "Off"),
+            SleepTimerChoice::Minutes(minutes) => write!(f, "{} min", minutes),
+        }
+    }
 }
 
 impl TrayUtility {
     fn new() -> (Self, Task<Message>) {
-        let protocol = Protocol::new_send().unwrap();
         let xdg = BaseDirectories::with_prefix("adh-rs");
         let slots = Slots::load_from_disk(&xdg);
-        let start_weights = slots.recall_slot(0);
+        // The daemon persists the chosen output device and volume in the config file.
+        // We use it until we know the real state of the daemon.
+        let config = Config::load_from_disk(&xdg);
 
-        let slf = Self {
+        let mut slf = Self {
             equalizer: Default::default(),
-            weights: start_weights,
-            protocol,
+            weights: slots.recall_slot(0),
+            protocol: None,
+            connecting: false,
+            pending: None,
             slots,
             xdg,
             last_segment_weight: None,
+            output_device: config.output_device,
+            volume: config.volume,
+            playing: None,
+            connected: false,
+            sleep_timer: None,
+            stream_lost: false,
         };
+        let task = slf.connect();
 
-        (slf, Task::none())
+        (slf, task)
+    }
+
+    /// Connect to the daemon in the background, so that the window shows the disconnected state meanwhile
+    /// instead of freezing while a daemon starts.
+    fn connect(&mut self) -> Task<Message> {
+        if self.connecting {
+            return Task::none();
+        }
+        self.connecting = true;
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let result = connect_daemon().map(Arc::new).map_err(|e| e.to_string());
+            tx.send(result).ok();
+        });
+        Task::perform(
+            async move {
+                rx.await
+                    .unwrap_or_else(|_| Err(String::from("The connecting thread died")))
+            },
+            Message::DaemonConnected,
+        )
+    }
+
+    /// Send a command to the daemon. If that fails, the daemon is probably gone, so we connect again
+    /// (starting a new daemon if necessary) and send the command once we are connected.
+    fn send(&mut self, command: protocol::GUICommand) -> Task<Message> {
+        if let Some(protocol) = &self.protocol {
+            match protocol.send(&command) {
+                Ok(()) => return Task::none(),
+                Err(e) => eprintln!("Sending to the daemon failed: {}. Reconnecting.", e),
+            }
+        }
+
+        self.disconnected();
+        self.pending = Some(command);
+        self.connect()
+    }
+
+    /// Ask the daemon for its state and show it.
+    fn refresh_status(&mut self) {
+        let Some(protocol) = &self.protocol else {
+            return;
+        };
+        match protocol.request(&protocol::GUICommand::GetStatus) {
+            Ok(Reply::Status(status)) => self.show_status(*status),
+            Ok(reply) => eprintln!("Unexpected reply to status request: {:?}", reply),
+            Err(e) => {
+                eprintln!("Failed to get the status of the daemon: {}", e);
+                self.disconnected();
+            }
+        }
+    }
+
+    /// Forget the socket of a daemon that we cannot reach anymore.
+    fn disconnected(&mut self) {
+        self.protocol = None;
+        self.connected = false;
+        self.playing = None;
+    }
+
+    fn show_status(&mut self, status: protocol::Status) {
+        if let Some(weights) = status.weights {
+            self.weights = weights;
+            self.equalizer.request_redraw();
+        }
+        self.output_device = status.output_device;
+        self.volume = status.volume;
+        self.playing = Some(status.playing);
+        // Noise without a stream means that the stream was lost.
+        self.stream_lost = status.weights.is_some() && status.stream.is_none();
+        self.show_sleep_timer(status.sleep_timer);
+    }
+
+    fn show_sleep_timer(&mut self, sleep_timer: Option<protocol::SleepTimerStatus>) {
+        self.sleep_timer = sleep_timer.map(|sleep_timer| {
+            let remaining = Duration::try_from_secs_f32(sleep_timer.remaining_secs).unwrap_or_default();
+            (SystemTime::now() + remaining, sleep_timer.fading)
+        });
     }
 
     /// Cleanup and return command to close the window.
@@ -95,7 +252,7 @@ impl TrayUtility {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum Message {
     ProcessCursorPosition(Point),
     OutOfBounds,
@@ -106,11 +263,35 @@ enum Message {
     ExitDaemon,
     SaveSlot(usize),
     RecallSlot(usize),
+    NextOutputDevice,
+    SetVolume(f32),
+    SetSleepTimer(SleepTimerChoice),
+    VolumeUp,
+    VolumeDown,
+    /// Another client (or we ourselves) changed the state of the daemon.
+    Daemon(DaemonEvent),
+    /// The event thread lost or regained the connection to the daemon.
+    Connected(bool),
+    /// Connecting to the daemon in the background finished.
+    DaemonConnected(Result<Arc<Protocol>, String>),
 }
 
 impl TrayUtility {
     fn title(&self) -> String {
-        String::from("Equalizer")
+        let mut title = match &self.output_device {
+            Some(device) => format!("Equalizer ({})", device.name),
+            None => String::from("Equalizer"),
+        };
+        if self.connecting {
+            title.push_str(" - connecting");
+        } else if !self.connected {
+            title.push_str(" - disconnected");
+        } else if self.stream_lost {
+            title.push_str(" - no sound");
+        } else if self.playing == Some(false) {
+            title.push_str(" - paused");
+        }
+        title
     }
 
     fn update(&mut self, message: Message) -> Task<Message> {
@@ -165,9 +346,11 @@ impl TrayUtility {
                 // Don't need to do anything because self.last_segment_index is already reset.
             }
             Message::ConfirmWeights => {
-                self.protocol
-                    .send(&protocol::GUICommand::SetWeights(self.weights))
-                    .unwrap();
+                let task = self.send(protocol::GUICommand::SetWeights(self.weights));
+                if self.connected {
+                    self.playing = Some(true);
+                }
+                return task;
             }
             Message::Clear => {
                 self.weights = Weights::default();
@@ -177,25 +360,142 @@ impl TrayUtility {
                 return self.window_close();
             }
             Message::ExitDaemon => {
-                self.protocol.send(&protocol::GUICommand::Quit).unwrap();
+                // No need to start a daemon just to quit it, so we don't reconnect here.
+                let result = match &self.protocol {
+                    Some(protocol) => protocol.send(&protocol::GUICommand::Quit),
+                    None => Err(anyhow::anyhow!("Not connected")),
+                };
+                if let Err(e) = result {
+                    eprintln!("Could not reach the daemon: {}", e);
+                }
                 return self.window_close();
             }
             Message::TogglePlay => {
-                self.protocol.send(&protocol::GUICommand::Toggle).unwrap();
+                let task = self.send(protocol::GUICommand::Toggle);
+                if self.connected {
+                    self.refresh_status();
+                }
+                return task;
             }
             Message::SaveSlot(idx) => self.slots.save_slot(idx, self.weights),
             Message::RecallSlot(idx) => {
                 self.weights = self.slots.recall_slot(idx);
                 self.equalizer.request_redraw();
             }
+            Message::NextOutputDevice => {
+                // Cycle through all output devices of the daemon, going back to the default device after the last one.
+                let Some(protocol) = &self.protocol else {
+                    eprintln!("Not connected to the daemon.");
+                    return Task::none();
+                };
+                let devices = match protocol.request(&protocol::GUICommand::ListOutputDevices) {
+                    Ok(Reply::OutputDevices(devices)) => devices,
+                    Ok(reply) => {
+                        eprintln!("Unexpected reply to the output device request: {:?}", reply);
+                        return Task::none();
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to get the output devices of the daemon: {}", e);
+                        return Task::none();
+                    }
+                };
+                let next_idx = match &self.output_device {
+                    Some(current) => devices.iter().position(|d| &d.device == current).map(|idx| idx + 1),
+                    None => Some(0),
+                };
+                self.output_device = next_idx.and_then(|idx| devices.get(idx)).map(|d| d.device.clone());
+
+                return self.send(protocol::GUICommand::SetOutputDevice(self.output_device.clone()));
+            }
+            Message::SetVolume(volume) => {
+                self.volume = volume.clamp(0.0, 1.0);
+                return self.send(protocol::GUICommand::SetVolume(self.volume));
+            }
+            Message::SetSleepTimer(SleepTimerChoice::Off) => {
+                return self.send(protocol::GUICommand::CancelSleepTimer);
+            }
+            Message::SetSleepTimer(SleepTimerChoice::Minutes(minutes)) => {
+                return self.send(protocol::GUICommand::SetSleepTimer(
+                    minutes as f32 * 60.0,
+                    SLEEP_TIMER_FADE_SECS,
+                ));
+            }
+            Message::VolumeUp => return self.update(Message::SetVolume(self.volume + VOLUME_STEP)),
+            Message::VolumeDown => return self.update(Message::SetVolume(self.volume - VOLUME_STEP)),
+            Message::Daemon(event) => match event {
+                DaemonEvent::WeightsChanged(weights) => {
+                    self.weights = weights;
+                    self.equalizer.request_redraw();
+                }
+                DaemonEvent::PlayingChanged(playing) => self.playing = Some(playing),
+                DaemonEvent::VolumeChanged(volume) => self.volume = volume,
+                DaemonEvent::OutputDeviceChanged(device) => self.output_device = device,
+                DaemonEvent::SleepTimerChanged(sleep_timer) => self.show_sleep_timer(sleep_timer),
+                DaemonEvent::StreamLost(error) => {
+                    eprintln!("The daemon lost its audio stream: {}", error);
+                    self.stream_lost = true;
+                }
+                DaemonEvent::StreamRecovered => self.stream_lost = false,
+                DaemonEvent::Unknown => {}
+            },
+            Message::Connected(true) => {
+                if self.protocol.is_none() {
+                    return self.connect();
+                }
+                // The daemon may have been restarted, so what we show could be outdated.
+                self.connected = true;
+                self.refresh_status();
+            }
+            Message::Connected(false) => self.disconnected(),
+            Message::DaemonConnected(result) => {
+                self.connecting = false;
+                match result {
+                    Ok(protocol) => {
+                        self.protocol = Some(protocol);
+                        self.connected = true;
+                        self.refresh_status();
+                        if let Some(command) = self.pending.take() {
+                            return self.send(command);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Could not reach the daemon: {}", e);
+                        self.pending = None;
+                    }
+                }
+            }
         };
 
         Task::none()
     }
 
     fn view(&self) -> Element<Message> {
+        let (width, _) = canvas_size();
+        let sleep_timer_choices: Vec<_> = std::iter::once(SleepTimerChoice::Off)
+            .chain(SLEEP_TIMER_MINUTES.map(SleepTimerChoice::Minutes))
+            .collect();
+        let sleep_timer = match self.sleep_timer {
+            Some((end, false)) => format!("Pauses at {}", clock_time(end)),
+            Some((end, true)) => format!("Fading out until {}", clock_time(end)),
+            None => String::new(),
+        };
+
         column![
             self.equalizer.view(&self.weights),
+            row![
+                text("Volume"),
+                slider(0.0..=1.0, self.volume, Message::SetVolume).step(0.01),
+            ]
+            .spacing(CANVAS_PADDING)
+            .width(width),
+            row![
+                text("Sleep timer"),
+                pick_list(sleep_timer_choices, None::<SleepTimerChoice>, Message::SetSleepTimer).placeholder("Set"),
+                text(sleep_timer),
+            ]
+            .spacing(CANVAS_PADDING)
+            .align_y(Alignment::Center)
+            .width(width),
             // button("Clear").padding(8).on_press(Message::Clear),
         ]
         .padding(CANVAS_PADDING)
@@ -210,11 +510,13 @@ impl TrayUtility {
         // 'D': exit daemon
         // 'P': pause playback
         // 'C': clear weights (go back to white noise)
+        // 'O': switch to the next output device
+        // '+'/'-': change volume
         // '0'..'9': recall slot
         // Ctrl + '0'..'9': save slot
         // TODO check that id matches this window?
         // TODO keypresses nt working at all
-        event::listen_with(|event, status, id| match (status, event) {
+        let keys = event::listen_with(|event, status, id| match (status, event) {
             /* TODO apparently this event is not emitted on mod+Shift+q in newer iced versions. Should debug. */
             (Status::Ignored, Event::Window(window::Event::CloseRequested)) => Some(Message::ExitApplication),
             (
@@ -231,6 +533,9 @@ impl TrayUtility {
                     'D' => Some(Message::ExitDaemon),
                     'P' => Some(Message::TogglePlay),
                     'C' => Some(Message::Clear),
+                    'O' => Some(Message::NextOutputDevice),
+                    '+' | '=' => Some(Message::VolumeUp),
+                    '-' => Some(Message::VolumeDown),
                     '0'..='9' => {
                         let idx = c.to_digit(10).unwrap() as usize;
                         if modifiers.control() {
@@ -243,10 +548,105 @@ impl TrayUtility {
                 }
             }
             (_, _) => None,
-        })
+        });
+
+        Subscription::batch([keys, Subscription::run(daemon_events)])
     }
 }
 
+/// Events pushed by the daemon when its state changes.
+/// We subscribe with a separate socket so that events don't get mixed up with the replies to our commands.
+/// The socket is blocking, so it gets its own thread.
+///
+/// Whenever no event arrived for a while we subscribe again. That is how we notice that the daemon is gone
+/// (sending fails) and it makes a restarted daemon know about us again.
+fn daemon_events() -> impl Stream<Item = Message> {
+    iced::stream::channel(16, |mut output| async move {
+        thread::spawn(move || {
+            let protocol = match Protocol::new_client() {
+                Ok(protocol) => protocol,
+                Err(e) => {
+                    eprintln!("Failed to create a socket for daemon events: {}", e);
+                    return;
+                }
+            };
+            let mut connected = None;
+
+            loop {
+                let subscribed = protocol
+                    .connect()
+                    .and_then(|()| protocol.send(&protocol::GUICommand::Subscribe))
+                    .is_ok();
+                if connected != Some(subscribed) {
+                    connected = Some(subscribed);
+                    if output.try_send(Message::Connected(subscribed)).is_err() {
+                        return;
+                    }
+                }
+                if !subscribed {
+                    thread::sleep(RECONNECT_INTERVAL);
+                    continue;
+                }
+
+                // Forward events until there is a pause.
+                while let Ok(Some(event)) = protocol.recv_event() {
+                    if let Err(e) = output.try_send(Message::Daemon(event)) {
+                        if e.is_disconnected() {
+                            return;
+                        }
+                        eprintln!("Dropping event of the daemon: {}", e);
+                    }
+                }
+            }
+        });
+
+        // The stream ends when this future returns, so it has to wait forever.
+        std::future::pending::<()>().await
+    })
+}
+
+/// Connect to the daemon, starting it if nobody is listening on its socket.
+/// With systemd socket activation the socket always exists and systemd starts the daemon for us.
+/// This blocks until the daemon is up, so it runs on its own thread.
+fn connect_daemon() -> Result<Protocol, anyhow::Error> {
+    let protocol = Protocol::new_client()?;
+    if protocol.connect().is_err() {
+        spawn_daemon()?;
+
+        let deadline = Instant::now() + DAEMON_STARTUP_TIMEOUT;
+        while let Err(e) = protocol.connect() {
+            if Instant::now() > deadline {
+                return Err(anyhow::anyhow!(
+                    "The daemon we started did not create its socket: {}",
+                    e
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    let daemon_version = protocol.handshake()?;
+    println!("Connected to daemon version {}.", daemon_version);
+    Ok(protocol)
+}
+
+/// Start the daemon in the background. It is put in its own process group so that it survives
+/// e.g. Ctrl+C in the terminal of the GUI.
+/// During development we use the executable in cargo's target/ directory, otherwise the one on the PATH.
+fn spawn_daemon() -> Result<(), anyhow::Error> {
+    let mut command = if is_development() {
+        let mut command = Command::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("target/debug/adh-daemon"));
+        command.arg("--dev");
+        command
+    } else {
+        Command::new("adh-daemon")
+    };
+
+    println!("Starting the daemon.");
+    command.process_group(0).spawn()?;
+    Ok(())
+}
+
 /// Some utility functions for converting coordinates
 mod util {
     use super::{CANVAS_HEIGHT, SEGMENTS_WEIGHT_MAX, SEGMENTS_WIDTH, WEIGHTS_NUM, WEIGHTS_PADDING_Y};
@@ -0,0 +1,239 @@
+//! Module to map noise sources onto the channels of the output device.
+//!
+//! A device can have any number of channels (1 for a single speaker, 2 for stereo, 6 for 5.1 etc.).
+//! Each noise source is an independent infinite stream of mono samples and the `ChannelMap` decides which
+//! source is played on which output channel.
+//! Optionally all sources are downmixed into a single signal that is played on every channel.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::samples::MonoSampleIter;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelMap {
+    /// A single noise source played on every channel.
+    #[default]
+    Duplicate,
+    /// Two independent noise sources. Even channels play the first and odd channels the second source.
+    Stereo,
+    /// Every output channel plays its own independent noise source.
+    Independent,
+    /// Output channel `i` plays source `map[i]`. If the device has more channels than the map, it wraps around.
+    Custom(Vec<usize>),
+}
+
+impl ChannelMap {
+    /// How many independent noise sources must be generated for a device with `channels` channels.
+    pub fn sources_needed(&self, channels: usize) -> usize {
+        match self {
+            ChannelMap::Duplicate => 1,
+            ChannelMap::Stereo => 2,
+            ChannelMap::Independent => channels.max(1),
+            ChannelMap::Custom(map) => map.iter().max().map_or(1, |max| max + 1),
+        }
+    }
+
+    /// Which noise source is played on output channel `channel`.
+    fn source_for(&self, channel: usize) -> usize {
+        match self {
+            ChannelMap::Duplicate => 0,
+            ChannelMap::Stereo => channel & 1,
+            ChannelMap::Independent => channel,
+            ChannelMap::Custom(map) if map.is_empty() => 0,
+            ChannelMap::Custom(map) => map[channel % map.len()],
+        }
+    }
+}
+
+/// A collection of noise sources that together produce one frame (one sample per channel) at a time.
+pub struct ChannelSamples {
+    sources: Vec<MonoSampleIter>,
+    map: ChannelMap,
+    downmix: bool,
+    /// The current sample of each source.
+    current: Vec<f32>,
+}
+
+impl ChannelSamples {
+    pub fn new(sources: Vec<MonoSampleIter>, map: ChannelMap, downmix: bool) -> Result<Self, anyhow::Error> {
+        if sources.is_empty() {
+            return Err(anyhow!("Empty sources"));
+        }
+        let current = vec![0.0; sources.len()];
+
+        Ok(Self {
+            sources,
+            map,
+            downmix,
+            current,
+        })
+    }
+
+    /// Whether these samples were generated for a device with `channels` channels, i.e. there is a source for every
+    /// channel that the map plays its own source on.
+    pub fn fits(&self, channels: usize) -> bool {
+        self.sources.len() == self.map.sources_needed(channels)
+    }
+
+    /// Fill `frame` with the next sample for each channel.
+    /// Every source advances exactly once per frame, even if it is not mapped to any channel, so that all
+    /// sources stay in sync.
+    /// Returns false (and fills the frame with silence) if one of the sources ran out of samples.
+    pub fn next_frame(&mut self, frame: &mut [f32]) -> bool {
+        for (current, source) in self.current.iter_mut().zip(self.sources.iter_mut()) {
+            match source.next() {
+                Some(s) => *current = s,
+                None => {
+                    frame.fill(0.0);
+                    return false;
+                }
+            }
+        }
+
+        if self.downmix {
+            // The sources are uncorrelated, so we scale by the square root to keep the same loudness.
+            // Peaks of several sources can still add up beyond full scale, which we clip here instead of the device.
+            let mono = (self.current.iter().sum::<f32>() / (self.current.len() as f32).sqrt()).clamp(-1.0, 1.0);
+            frame.fill(mono);
+        } else {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let source = self.map.source_for(channel) % self.current.len();
+                *sample = self.current[source];
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generator::{gen_channel_samples, gen_noise_source},
+        Weights,
+    };
+
+    fn constant_sources(values: &[f32]) -> Vec<MonoSampleIter> {
+        values
+            .iter()
+            .map(|value| Box::new(std::iter::repeat(*value)) as MonoSampleIter)
+            .collect()
+    }
+
+    /// The first `frames` frames of independent noise for a device with `channels` channels, one Vec per channel.
+    fn independent_channels(channels: usize, frames: usize) -> Vec<Vec<f32>> {
+        let mut samples = gen_channel_samples(&Weights::default(), &ChannelMap::Independent, false, channels).unwrap();
+        let mut by_channel = vec![Vec::with_capacity(frames); channels];
+        let mut frame = vec![0.0; channels];
+        for _ in 0..frames {
+            assert!(samples.next_frame(&mut frame));
+            for (channel, sample) in by_channel.iter_mut().zip(&frame) {
+                channel.push(*sample);
+            }
+        }
+        by_channel
+    }
+
+    #[test]
+    fn maps_are_parsed_from_the_config() {
+        assert_eq!(
+            serde_json::from_str::<ChannelMap>(r#""Duplicate""#).unwrap(),
+            ChannelMap::Duplicate
+        );
+        assert_eq!(
+            serde_json::from_str::<ChannelMap>(r#""Independent""#).unwrap(),
+            ChannelMap::Independent
+        );
+        assert_eq!(
+            serde_json::from_str::<ChannelMap>(r#"{"Custom":[0,1,0,2]}"#).unwrap(),
+            ChannelMap::Custom(vec![0, 1, 0, 2])
+        );
+        assert!(serde_json::from_str::<ChannelMap>(r#""Surround""#).is_err());
+        assert!(serde_json::from_str::<ChannelMap>(r#"{"Custom":[-1]}"#).is_err());
+    }
+
+    #[test]
+    fn sources_are_mapped_onto_channels() {
+        assert_eq!(ChannelMap::Duplicate.sources_needed(6), 1);
+        assert_eq!(ChannelMap::Stereo.sources_needed(6), 2);
+        assert_eq!(ChannelMap::Independent.sources_needed(6), 6);
+        assert_eq!(ChannelMap::Independent.sources_needed(0), 1);
+        assert_eq!(ChannelMap::Custom(vec![0, 2, 0]).sources_needed(2), 3);
+        assert_eq!(ChannelMap::Custom(Vec::new()).sources_needed(2), 1);
+
+        let mut samples = ChannelSamples::new(
+            constant_sources(&[0.1, 0.2, 0.3]),
+            ChannelMap::Custom(vec![2, 0, 1]),
+            false,
+        )
+        .unwrap();
+        let mut frame = [0.0; 5];
+        assert!(samples.next_frame(&mut frame));
+        // The map wraps around for the channels after the third.
+        assert_eq!(frame, [0.3, 0.1, 0.2, 0.3, 0.1]);
+    }
+
+    #[test]
+    fn quad_and_surround_channels_are_independent() {
+        for channels in [4, 6] {
+            let by_channel = independent_channels(channels, 4096);
+            for (i, a) in by_channel.iter().enumerate() {
+                assert!(a.iter().any(|s| *s != 0.0), "Channel {} of {} is silent", i, channels);
+                for (j, b) in by_channel.iter().enumerate().skip(i + 1) {
+                    assert_ne!(a, b, "Channels {} and {} of {} are copies", i, j, channels);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn downmix_keeps_the_loudness_of_uncorrelated_sources() {
+        let mut frame = [0.0; 2];
+        let mut samples =
+            ChannelSamples::new(constant_sources(&[0.3, -0.1, 0.2, 0.0]), ChannelMap::Independent, true).unwrap();
+        assert!(samples.next_frame(&mut frame));
+        // (0.3 - 0.1 + 0.2 + 0.0) / sqrt(4)
+        assert!((frame[0] - 0.2).abs() < 1e-6, "{:?}", frame);
+        assert_eq!(frame[0], frame[1]);
+
+        let mut samples = ChannelSamples::new(constant_sources(&[0.5, -0.5]), ChannelMap::Stereo, true).unwrap();
+        assert!(samples.next_frame(&mut frame));
+        assert_eq!(frame, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn downmix_does_not_clip() {
+        let mut frame = [0.0; 1];
+        for value in [1.0, -1.0] {
+            let mut samples =
+                ChannelSamples::new(constant_sources(&[value; 4]), ChannelMap::Independent, true).unwrap();
+            assert!(samples.next_frame(&mut frame));
+            assert_eq!(frame, [value]);
+        }
+
+        let mut samples = ChannelSamples::new(
+            (0..6).map(|_| gen_noise_source(&Weights::default()).unwrap()).collect(),
+            ChannelMap::Independent,
+            true,
+        )
+        .unwrap();
+        for _ in 0..48_000 {
+            assert!(samples.next_frame(&mut frame));
+            assert!((-1.0..=1.0).contains(&frame[0]), "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn exhausted_sources_end_in_silence() {
+        let sources: Vec<MonoSampleIter> = vec![Box::new(std::iter::repeat(0.5)), Box::new(std::iter::once(0.5))];
+        let mut samples = ChannelSamples::new(sources, ChannelMap::Stereo, false).unwrap();
+        let mut frame = [0.0; 2];
+        assert!(samples.next_frame(&mut frame));
+        assert_eq!(frame, [0.5, 0.5]);
+        assert!(!samples.next_frame(&mut frame));
+        assert_eq!(frame, [0.0, 0.0]);
+        assert!(ChannelSamples::new(Vec::new(), ChannelMap::Duplicate, false).is_err());
+    }
+}
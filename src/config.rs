@@ -0,0 +1,150 @@
+//! Configuration of the daemon.
+//!
+//! The config file is read from the xdg config directory when the daemon starts.
+//! Settings that can be changed at runtime (like the output device and volume) are written back by the daemon.
+//! Missing fields fall back on their default values so that old config files keep working.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    time::Duration,
+};
+use xdg::BaseDirectories;
+
+use crate::{alarm::Alarm, audio_bridge::OutputDevice, channels::ChannelMap, dither::DitherMode};
+
+const CONFIG_FILENAME: &str = "config.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Which noise source is played on which output channel.
+    pub channel_map: ChannelMap,
+    /// Mix all noise sources down into one signal that is played on every channel.
+    /// Useful for devices with a single speaker.
+    pub mono_downmix: bool,
+    /// The output device chosen by the user. If it is None or the device disappears, we use the default device.
+    pub output_device: Option<OutputDevice>,
+    /// The master volume in 0..1.
+    pub volume: f32,
+    /// How many seconds it takes to fade in when resuming and to fade out when pausing or quitting.
+    pub fade_secs: f32,
+    /// The buffer size of the output stream in frames. None means the default of the device.
+    pub buffer_frames: Option<u32>,
+    /// The latency of the output stream in milliseconds, used to compute the buffer size if `buffer_frames` is None.
+    pub latency_ms: Option<f32>,
+    /// How samples are dithered when the output device uses an integer sample format.
+    pub dither: DitherMode,
+    /// Which controllers of a MIDI controller change which setting, learned with `GUICommand::MidiLearn`.
+    pub midi_mappings: Vec<MidiMapping>,
+    /// The MQTT broker to publish the state to and receive commands from, None to not use MQTT.
+    pub mqtt: Option<MqttConfig>,
+    /// The wake-up alarms set with `GUICommand::SetAlarm`, ordered by the time they ring.
+    pub alarms: Vec<Alarm>,
+}
+
+/// What a MIDI controller changes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MidiTarget {
+    /// The weight of one frequency band.
+    Band(usize),
+    Volume,
+}
+
+/// Maps a control change (CC) controller on a MIDI channel to a setting.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MidiMapping {
+    /// The MIDI channel in 0..16.
+    pub channel: u8,
+    /// The controller number in 0..128.
+    pub controller: u32,
+    pub target: MidiTarget,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel_map: Default::default(),
+            mono_downmix: false,
+            output_device: None,
+            volume: 1.0,
+            fade_secs: 1.0,
+            buffer_frames: None,
+            latency_ms: None,
+            dither: Default::default(),
+            midi_mappings: Vec::new(),
+            mqtt: None,
+            alarms: Vec::new(),
+        }
+    }
+}
+
+/// The connection to an MQTT broker, e.g. for Home Assistant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// The address of the broker as host:port.
+    pub broker: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The prefix of the topics of the daemon.
+    pub topic: String,
+    /// The prefix under which Home Assistant looks for discovery messages, None to not publish any.
+    pub discovery_prefix: Option<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            broker: String::from("localhost:1883"),
+            username: None,
+            password: None,
+            topic: String::from("adh-rs"),
+            discovery_prefix: Some(String::from("homeassistant")),
+        }
+    }
+}
+
+impl Config {
+    pub fn fade_duration(&self) -> Duration {
+        Duration::try_from_secs_f32(self.fade_secs).unwrap_or_default()
+    }
+
+    pub fn write_to_disk(&self, xdg_dirs: &BaseDirectories) {
+        let inner = || -> Result<(), anyhow::Error> {
+            let buf = serde_json::to_vec_pretty(&self)?;
+            let path = xdg_dirs.place_config_file(CONFIG_FILENAME)?;
+            let mut f = File::create(path)?;
+            f.write_all(&buf)?;
+            f.flush()?;
+            Ok(())
+        };
+
+        if let Err(e) = inner() {
+            eprintln!("Writing config failed: {}", e);
+        }
+    }
+
+    pub fn load_from_disk(xdg_dirs: &BaseDirectories) -> Self {
+        let inner = || -> Result<Config, anyhow::Error> {
+            let path = xdg_dirs
+                .find_config_file(CONFIG_FILENAME)
+                .ok_or(anyhow!("Config file not found."))?;
+            let mut f = File::open(path)?;
+            let mut buf = Vec::new();
+            f.read_to_end(&mut buf)?;
+            let config = serde_json::from_slice(&buf)?;
+            Ok(config)
+        };
+
+        match inner() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{}", e);
+                Config::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Module for dithering the output before it is converted to an integer sample format.
+//!
+//! Converting to e.g. `i16` rounds every sample to the nearest step. For quiet noise at a low volume the
+//! rounding error is correlated with the signal and can be heard as graininess.
+//! Adding a tiny amount of random noise with a triangular probability distribution (TPDF) of +-1 step before
+//! rounding turns the error into constant, signal-independent white noise.
+//! Optionally the error is noise-shaped, i.e. fed back into the next sample so that it moves to higher
+//! frequencies where it is less audible.
+//!
+//! Float formats and integer formats with more precision than an f32 are never dithered.
+
+use cpal::SampleFormat;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Formats with more bits than this are not dithered because an f32 cannot represent their steps anyway.
+const MAX_DITHER_BITS: u32 = 24;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DitherMode {
+    /// Just round to the nearest step.
+    Off,
+    /// Triangular-PDF dither of +-1 step.
+    #[default]
+    Tpdf,
+    /// Triangular-PDF dither with first-order noise shaping.
+    NoiseShaped,
+}
+
+/// Dither state of a stream. Keeps the quantization error of the last frame for noise shaping.
+pub struct Dither {
+    mode: DitherMode,
+    /// The size of one step of the output format, relative to the -1..1 range of f32 samples.
+    step: f32,
+    errors: Vec<f32>,
+    rng: SmallRng,
+}
+
+impl Dither {
+    /// Dither for a stream with `channels` channels in `format`.
+    /// For formats that don't need dithering the mode is ignored and the frames are left untouched.
+    pub fn new(mode: DitherMode, format: SampleFormat, channels: usize) -> Self {
+        let mode = match integer_bits(format) {
+            Some(bits) if bits <= MAX_DITHER_BITS => mode,
+            _ => DitherMode::Off,
+        };
+        let step = integer_bits(format).map_or(0.0, |bits| 2.0f32.powi(1 - bits as i32));
+
+        Self {
+            mode,
+            step,
+            errors: vec![0.0; channels],
+            rng: SmallRng::from_os_rng(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode != DitherMode::Off
+    }
+
+    /// Dither and quantize one frame in place so that converting it to the output format is exact.
+    pub fn apply(&mut self, frame: &mut [f32]) {
+        if !self.is_active() {
+            return;
+        }
+
+        for (sample, error) in frame.iter_mut().zip(self.errors.iter_mut()) {
+            let shaped = match self.mode {
+                DitherMode::NoiseShaped => *sample - *error,
+                _ => *sample,
+            };
+            // The difference of two uniform random numbers has a triangular distribution in -1..1.
+            let noise = self.rng.random::<f32>() - self.rng.random::<f32>();
+            let quantized = ((shaped / self.step + noise).round() * self.step).clamp(-1.0, 1.0 - self.step);
+            // Clipping can make the error arbitrarily large, which must not be fed back.
+            *error = (quantized - shaped).clamp(-2.0 * self.step, 2.0 * self.step);
+            *sample = quantized;
+        }
+    }
+}
+
+/// The number of bits of an integer sample format, or None for float formats.
+fn integer_bits(format: SampleFormat) -> Option<u32> {
+    match format {
+        SampleFormat::I24 => Some(24),
+        format if format.is_int() || format.is_uint() => Some(format.sample_size() as u32 * 8),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::Sample;
+
+    const I16_STEP: f32 = 1.0 / 32768.0;
+
+    /// Dither `samples` one mono frame at a time.
+    fn dither(mode: DitherMode, samples: &[f32]) -> Vec<f32> {
+        let mut dither = Dither::new(mode, SampleFormat::I16, 1);
+        samples
+            .iter()
+            .map(|sample| {
+                let mut frame = [*sample];
+                dither.apply(&mut frame);
+                frame[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_integer_formats_are_dithered() {
+        for format in [
+            SampleFormat::I8,
+            SampleFormat::I16,
+            SampleFormat::U16,
+            SampleFormat::I24,
+        ] {
+            assert!(Dither::new(DitherMode::Tpdf, format, 2).is_active(), "{:?}", format);
+        }
+        for format in [
+            SampleFormat::F32,
+            SampleFormat::F64,
+            SampleFormat::I32,
+            SampleFormat::I64,
+        ] {
+            assert!(!Dither::new(DitherMode::Tpdf, format, 2).is_active(), "{:?}", format);
+        }
+        assert!(!Dither::new(DitherMode::Off, SampleFormat::I16, 2).is_active());
+
+        let mut frame = [0.123456, -0.5];
+        Dither::new(DitherMode::NoiseShaped, SampleFormat::F32, 2).apply(&mut frame);
+        assert_eq!(frame, [0.123456, -0.5]);
+    }
+
+    #[test]
+    fn tpdf_stays_within_one_step() {
+        let silence = dither(DitherMode::Tpdf, &[0.0; 10_000]);
+        assert!(
+            silence.iter().all(|s| [-I16_STEP, 0.0, I16_STEP].contains(s)),
+            "{:?}",
+            silence
+        );
+        assert!(silence.iter().any(|s| *s != 0.0));
+
+        let samples: Vec<f32> = (0..10_000).map(|i| (i as f32 * 0.01).sin() * 0.5).collect();
+        for (dithered, sample) in dither(DitherMode::Tpdf, &samples).iter().zip(&samples) {
+            // The noise is less than a step and rounding adds at most half a step.
+            assert!((dithered - sample).abs() <= 1.5 * I16_STEP, "{} {}", dithered, sample);
+            assert_eq!((dithered / I16_STEP).fract(), 0.0);
+        }
+    }
+
+    #[test]
+    fn quiet_signals_survive() {
+        let quiet = [0.3 * I16_STEP; 10_000];
+        // Without dither, converting to the output format truncates everything to silence.
+        let converted = |samples: Vec<f32>| samples.into_iter().map(|s| s.to_sample::<i16>()).collect::<Vec<_>>();
+        assert!(converted(dither(DitherMode::Off, &quiet)).iter().all(|s| *s == 0));
+
+        for mode in [DitherMode::Tpdf, DitherMode::NoiseShaped] {
+            let dithered = dither(mode, &quiet);
+            assert!(converted(dithered.clone()).iter().any(|s| *s != 0), "{:?}", mode);
+            // On average the dithered signal keeps the level that rounding alone would lose.
+            let mean = dithered.iter().sum::<f32>() / dithered.len() as f32;
+            assert!(
+                (mean - quiet[0]).abs() < 0.05 * I16_STEP,
+                "{:?} {}",
+                mode,
+                mean / I16_STEP
+            );
+        }
+    }
+
+    #[test]
+    fn full_scale_is_clipped_to_the_format() {
+        let loud = dither(DitherMode::NoiseShaped, &[1.0, -1.0, 1.0, -1.0]);
+        assert!(loud.iter().all(|s| (-1.0..=1.0 - I16_STEP).contains(s)), "{:?}", loud);
+    }
+}
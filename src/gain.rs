@@ -0,0 +1,278 @@
+//! Module for the gain stage that is applied to every frame in the audio callback.
+//!
+//! The daemon changes the volume through a `GainControl` which is shared with the audio callback.
+//! The `GainStage` in the audio callback does not jump to a new volume but ramps to it over a short time.
+//! Otherwise moving the volume slider results in audible steps ("zipper noise").
+//!
+//! Pausing and resuming also happens here instead of pausing the stream, because not every backend supports
+//! pausing and stopping a stream cuts the audio hard, which produces a click.
+//! Instead we fade out to silence over a configurable time and stop advancing the samples while paused.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How long it takes to ramp from silence to full volume.
+const VOLUME_RAMP_SECS: f32 = 0.05;
+
+/// Handle to control the gain of the audio callback from another thread.
+/// All values are stored as the bits of an f32 so that we can use atomics instead of a lock.
+#[derive(Debug, Clone)]
+pub struct GainControl {
+    inner: Arc<GainControlInner>,
+}
+
+#[derive(Debug)]
+struct GainControlInner {
+    volume: AtomicU32,
+    /// 1.0 when playing and 0.0 when paused.
+    fade_target: AtomicU32,
+    /// How long it takes to fade from silence to full volume or back.
+    fade_secs: AtomicU32,
+    /// The current fade level reported back by the audio callback.
+    fade_level: AtomicU32,
+}
+
+impl GainControl {
+    /// A new gain control starts out paused.
+    pub fn new(volume: f32) -> Self {
+        Self {
+            inner: Arc::new(GainControlInner {
+                volume: AtomicU32::new(clamp_volume(volume).to_bits()),
+                fade_target: AtomicU32::new(0.0f32.to_bits()),
+                fade_secs: AtomicU32::new(0.0f32.to_bits()),
+                fade_level: AtomicU32::new(0.0f32.to_bits()),
+            }),
+        }
+    }
+
+    /// Set the volume in 0..1.
+    pub fn set_volume(&self, volume: f32) {
+        self.inner
+            .volume
+            .store(clamp_volume(volume).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.inner.volume.load(Ordering::Relaxed))
+    }
+
+    /// Fade out to silence over `fade`.
+    pub fn pause(&self, fade: Duration) {
+        self.fade_to(0.0, fade);
+    }
+
+    /// Fade in to the full volume over `fade`.
+    pub fn resume(&self, fade: Duration) {
+        self.fade_to(1.0, fade);
+    }
+
+    fn fade_to(&self, target: f32, fade: Duration) {
+        self.inner
+            .fade_secs
+            .store(fade.as_secs_f32().to_bits(), Ordering::Relaxed);
+        self.inner.fade_target.store(target.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        f32::from_bits(self.inner.fade_target.load(Ordering::Relaxed)) == 0.0
+    }
+
+    /// The fade level in 0..1 that the audio callback currently applies.
+    pub fn fade_level(&self) -> f32 {
+        f32::from_bits(self.inner.fade_level.load(Ordering::Relaxed))
+    }
+}
+
+/// Clamp the volume into 0..1. Unlike `f32::clamp`, this also turns NaN into silence.
+fn clamp_volume(volume: f32) -> f32 {
+    if volume.is_nan() {
+        0.0
+    } else {
+        volume.clamp(0.0, 1.0)
+    }
+}
+
+/// Move `current` towards `target` by at most `step`.
+fn ramp(current: f32, target: f32, step: f32) -> f32 {
+    if current < target {
+        (current + step).min(target)
+    } else {
+        (current - step).max(target)
+    }
+}
+
+/// The gain stage of one stream.
+pub struct GainStage {
+    control: GainControl,
+    sample_rate: f32,
+    volume: f32,
+    fade: f32,
+}
+
+impl GainStage {
+    /// The stage continues at the fade level of the previous stream, so rebuilding a stream does not
+    /// restart a fade.
+    pub fn new(control: GainControl, sample_rate: u32) -> Self {
+        let volume = control.volume();
+        let fade = control.fade_level();
+        Self {
+            control,
+            sample_rate: sample_rate as f32,
+            volume,
+            fade,
+        }
+    }
+
+    /// Whether we have completely faded out. Then the samples don't need to be advanced.
+    pub fn is_silent(&self) -> bool {
+        self.fade == 0.0 && self.control.is_paused()
+    }
+
+    /// Apply the gain to all frames in `buf` which contains interleaved samples for `channels` channels.
+    pub fn apply(&mut self, buf: &mut [f32], channels: usize) {
+        let volume_target = self.control.volume();
+        let volume_step = 1.0 / (VOLUME_RAMP_SECS * self.sample_rate);
+        let fade_target = f32::from_bits(self.control.inner.fade_target.load(Ordering::Relaxed));
+        let fade_secs = f32::from_bits(self.control.inner.fade_secs.load(Ordering::Relaxed));
+        let fade_step = if fade_secs > 0.0 {
+            1.0 / (fade_secs * self.sample_rate)
+        } else {
+            1.0
+        };
+
+        for frame in buf.chunks_mut(channels) {
+            self.volume = ramp(self.volume, volume_target, volume_step);
+            self.fade = ramp(self.fade, fade_target, fade_step);
+
+            let gain = self.volume * self.fade;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+
+        self.control
+            .inner
+            .fade_level
+            .store(self.fade.to_bits(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+
+    /// Apply the gain to `frames` mono frames of full scale and return the gains that were applied.
+    fn gains(stage: &mut GainStage, frames: usize) -> Vec<f32> {
+        let mut buf = vec![1.0; frames];
+        stage.apply(&mut buf, 1);
+        buf
+    }
+
+    fn playing(volume: f32) -> GainControl {
+        let control = GainControl::new(volume);
+        control.resume(Duration::ZERO);
+        control
+    }
+
+    #[test]
+    fn volume_ramps_to_the_target() {
+        let control = playing(0.0);
+        let mut stage = GainStage::new(control.clone(), SAMPLE_RATE);
+        control.set_volume(0.8);
+
+        // The full ramp takes 50 frames at 1000 Hz, so 0.8 takes 40.
+        let ramp_frames = (0.8 * VOLUME_RAMP_SECS * SAMPLE_RATE as f32).ceil() as usize;
+        let up = gains(&mut stage, 2 * ramp_frames);
+        assert!(up.windows(2).all(|w| w[0] <= w[1]), "{:?}", up);
+        assert!(up[0] > 0.0 && up[0] < 0.1, "{:?}", up);
+        assert!(up[ramp_frames - 2] < 0.8, "{:?}", up);
+        assert!(up[ramp_frames..].iter().all(|g| (g - 0.8).abs() < 1e-5), "{:?}", up);
+
+        control.set_volume(0.2);
+        let down = gains(&mut stage, 2 * ramp_frames);
+        assert!(down.windows(2).all(|w| w[0] >= w[1]), "{:?}", down);
+        assert!(down[0] > 0.7, "{:?}", down);
+        assert!((down.last().unwrap() - 0.2).abs() < 1e-5, "{:?}", down);
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        let control = GainControl::new(2.0);
+        assert_eq!(control.volume(), 1.0);
+        control.set_volume(-0.5);
+        assert_eq!(control.volume(), 0.0);
+        control.set_volume(f32::NAN);
+        assert_eq!(control.volume(), 0.0);
+        control.set_volume(f32::INFINITY);
+        assert_eq!(control.volume(), 1.0);
+        assert_eq!(GainControl::new(f32::NAN).volume(), 0.0);
+    }
+
+    #[test]
+    fn nan_volume_does_not_reach_the_samples() {
+        let control = playing(1.0);
+        let mut stage = GainStage::new(control.clone(), SAMPLE_RATE);
+        control.set_volume(f32::NAN);
+        let gains = gains(&mut stage, 100);
+        assert!(gains.iter().all(|g| g.is_finite()), "{:?}", gains);
+        assert_eq!(*gains.last().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn fade_out_reaches_silence_and_finishes() {
+        let control = playing(1.0);
+        let mut stage = GainStage::new(control.clone(), SAMPLE_RATE);
+        gains(&mut stage, 10);
+        assert_eq!(control.fade_level(), 1.0);
+        assert!(!stage.is_silent());
+
+        // 0.1 s at 1000 Hz are 100 frames.
+        control.pause(Duration::from_millis(100));
+        let fade = gains(&mut stage, 90);
+        assert!(fade.windows(2).all(|w| w[0] > w[1]), "{:?}", fade);
+        assert!(!stage.is_silent());
+        assert!(control.fade_level() > 0.0);
+
+        let rest = gains(&mut stage, 20);
+        assert!(rest[10..].iter().all(|g| *g == 0.0), "{:?}", rest);
+        assert_eq!(control.fade_level(), 0.0);
+        assert!(stage.is_silent());
+    }
+
+    #[test]
+    fn resuming_fades_in_and_continues_on_a_new_stage() {
+        let control = GainControl::new(1.0);
+        let mut stage = GainStage::new(control.clone(), SAMPLE_RATE);
+        assert!(stage.is_silent());
+        assert!(gains(&mut stage, 10).iter().all(|g| *g == 0.0));
+
+        control.resume(Duration::from_millis(100));
+        assert!(!stage.is_silent());
+        let fade = gains(&mut stage, 50);
+        assert!(fade.windows(2).all(|w| w[0] < w[1]), "{:?}", fade);
+
+        // A rebuilt stream picks up the fade where the old one left it.
+        let level = control.fade_level();
+        let mut stage = GainStage::new(control.clone(), SAMPLE_RATE);
+        let fade = gains(&mut stage, 60);
+        assert!(fade[0] > level && fade[0] < level + 0.02, "{} {:?}", level, fade);
+        assert_eq!(*fade.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn pausing_without_fade_is_immediate() {
+        let control = playing(1.0);
+        let mut stage = GainStage::new(control.clone(), SAMPLE_RATE);
+        gains(&mut stage, 10);
+        control.pause(Duration::ZERO);
+        assert_eq!(gains(&mut stage, 1), [0.0]);
+        assert!(stage.is_silent());
+    }
+}
@@ -3,14 +3,21 @@ use rand::{self, distr::Distribution, SeedableRng};
 use rustdct::DctPlanner;
 
 use crate::{
-    samples::{Sample, CHUNK_SAMPLES},
+    channels::{ChannelMap, ChannelSamples},
+    samples::{BlendType, BlendingSamples, MonoSampleIter, Sample, CHUNK_SAMPLES, SAMPLE_RATE},
     Weights, WEIGHTS_NUM,
 };
 
-const SAMPLE_FREQ: f32 = 44_100.0;
+const SAMPLE_FREQ: f32 = SAMPLE_RATE as f32;
 const MIN_FREQ: f32 = 20.0;
 const MAX_FREQ: f32 = 20_000.0;
 
+// The center frequency of weight band `i`. The bands are spaced logarithmically between MIN_FREQ and MAX_FREQ.
+pub fn band_freq(i: usize) -> f32 {
+    let freq_ratio = (MAX_FREQ / MIN_FREQ).powf(1.0 / (WEIGHTS_NUM - 1) as f32);
+    MIN_FREQ * freq_ratio.powi(i as i32)
+}
+
 // For a frequency in 0..SAMPLE_FREQ/2, compute a weight.
 // The weight is the linear interpolation between the two defined weights in `weights`.
 pub fn get_freq_weight(weights: &Weights, freq: f32) -> f32 {
@@ -55,6 +62,29 @@ pub fn gen_weighted_noise(weights: &Weights) -> Sample {
     Sample::new(freqs).unwrap()
 }
 
+// Generate an infinite noise source by blending between two noise chunks.
+pub fn gen_noise_source(weights: &Weights) -> Result<MonoSampleIter, anyhow::Error> {
+    let samples1 = gen_weighted_noise(weights);
+    let samples2 = gen_weighted_noise(weights);
+    BlendingSamples::new(vec![samples1, samples2])?
+        .with_blend(BlendType::Sigmoid)
+        .into_iter()
+}
+
+// Generate as many independent noise sources as `channel_map` needs for a device with `channels` channels.
+pub fn gen_channel_samples(
+    weights: &Weights,
+    channel_map: &ChannelMap,
+    mono_downmix: bool,
+    channels: usize,
+) -> Result<ChannelSamples, anyhow::Error> {
+    let sources = (0..channel_map.sources_needed(channels))
+        .map(|_| gen_noise_source(weights))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    ChannelSamples::new(sources, channel_map.clone(), mono_downmix)
+}
+
 // Inverse discrete cosine transform to transform frequencies back into audio waves.
 // rustdct does not apply normalization, so we do it explicitly here.
 pub fn idct(fs: &mut [f32]) {
@@ -5,11 +5,19 @@ use std::{
     path::{Path, PathBuf},
 };
 
+pub mod alarm;
 pub mod audio_bridge;
+pub mod channels;
+pub mod config;
+pub mod dither;
+pub mod gain;
 pub mod generator;
+pub mod presets;
 pub mod protocol;
 pub mod samples;
+pub mod sink;
 pub mod slots;
+pub mod wav;
 
 pub const WEIGHTS_NUM: usize = 32;
 pub const SEGMENTS_WEIGHT_MAX: f32 = 1.0;
@@ -25,9 +33,18 @@ lazy_static! {
             Path::new(&xdg_runtime_dir).join("adh-rs.sock").to_owned()
         }
     };
+    /// The stream socket for messages of any size, next to the datagram socket.
+    static ref STREAM_SOCKET_PATH: PathBuf = {
+        if is_development() {
+            Path::new("/tmp/adh-rs.stream.sock").to_owned()
+        } else {
+            let xdg_runtime_dir = env::var("XDG_RUNTIME_DIR").expect("XDG_RUNTIME_DIR is unset");
+            Path::new(&xdg_runtime_dir).join("adh-rs.stream.sock").to_owned()
+        }
+    };
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Weights {
     pub v: [f32; WEIGHTS_NUM],
 }
@@ -44,15 +61,7 @@ impl Default for Weights {
 /// Earlier we used cfg!(debug_assertions) but that's not great if we
 /// want to locally test the release versions. So we use a separate command
 /// line argument.
+/// The binaries handle their other command line arguments themselves.
 pub fn is_development() -> bool {
-    // a.d. TODO better command line argument handling
-    if let Some(arg) = std::env::args().nth(1) {
-        if &arg == "--dev" {
-            true
-        } else {
-            panic!("Unknown command line argument: {}", arg);
-        }
-    } else {
-        false
-    }
+    std::env::args().skip(1).any(|arg| arg == "--dev")
 }
@@ -0,0 +1,49 @@
+//! Named presets for the common colors of noise.
+//!
+//! The color of noise describes how its power changes with the frequency f.
+//! The weights scale the amplitude, which is the square root of the power, so e.g. pink noise
+//! with a power of 1/f gets weights of 1/sqrt(f).
+
+use crate::{generator::band_freq, Weights, WEIGHTS_NUM};
+
+/// Names of all presets, in order of increasing brightness.
+pub const PRESET_NAMES: [&str; 5] = ["brown", "pink", "white", "blue", "violet"];
+
+/// The weights of the preset called `name`, or None if there is no such preset.
+pub fn preset(name: &str) -> Option<Weights> {
+    // The exponent of the frequency in the amplitude.
+    let exponent = match name {
+        "brown" => -1.0,
+        "pink" => -0.5,
+        "white" => 0.0,
+        "blue" => 0.5,
+        "violet" => 1.0,
+        _ => return None,
+    };
+    Some(power_law(exponent))
+}
+
+/// The name of the preset that has the weights `weights`, if any.
+pub fn preset_name(weights: &Weights) -> Option<&'static str> {
+    PRESET_NAMES.into_iter().find(|name| {
+        preset(name).is_some_and(|preset| preset.v.iter().zip(weights.v.iter()).all(|(a, b)| (a - b).abs() < 1e-4))
+    })
+}
+
+/// Weights proportional to f^exponent, scaled so that the loudest band has weight 1.
+fn power_law(exponent: f32) -> Weights {
+    let mut weights = Weights::default();
+    for (i, w) in weights.v.iter_mut().enumerate() {
+        *w = band_freq(i).powf(exponent);
+    }
+
+    let max = if exponent < 0.0 {
+        weights.v[0]
+    } else {
+        weights.v[WEIGHTS_NUM - 1]
+    };
+    for w in weights.v.iter_mut() {
+        *w /= max;
+    }
+    weights
+}
@@ -1,17 +1,225 @@
+//! Protocol between the daemon and its clients (the GUI and scripts).
+//!
+//! Every message is wrapped in an envelope: the magic bytes `ADH`, the protocol version as a little-endian u16
+//! and then the message as JSON, the same as on the JSON socket.
+//! The daemon replies with `Reply::Error` to messages with another version or that it can't decode,
+//! instead of silently misinterpreting them.
+//!
+//! JSON names every field and variant, so peers of different builds stay compatible when messages grow:
+//! - New fields get `#[serde(default)]`, so that messages of older peers without them still decode.
+//!   Older peers ignore fields they don't know.
+//! - New variants of `GUICommand`, `Reply` and `Event` decode as their `Unknown` variant on older peers.
+//!   The daemon answers an unknown command with an error and clients ignore unknown events.
+//!
+//! Only removing or changing the meaning of fields and variants requires increasing `PROTOCOL_VERSION`.
+//!
+//! There are two transports for the same messages:
+//! - `Protocol` sends one message per datagram, so messages are limited to a fixed buffer size.
+//! - `StreamProtocol` sends messages over a stream socket, each prefixed with its length as a little-endian u32,
+//!   so messages can be (almost) arbitrarily large.
+
 use anyhow::anyhow;
-use serde::{Deserialize, Serialize};
-use std::{fs, os::unix::net::UnixDatagram};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{ErrorKind, Read, Write},
+    os::{
+        fd::AsRawFd,
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::PermissionsExt,
+            net::{SocketAddr, UnixDatagram, UnixListener, UnixStream},
+        },
+    },
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
 
-use crate::{Weights, SOCKET_PATH};
+use crate::{
+    alarm::Alarm,
+    audio_bridge::{OutputDevice, OutputDeviceInfo},
+    config::MidiTarget,
+    sink::StreamStats,
+    Weights, SOCKET_PATH, STREAM_SOCKET_PATH,
+};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum GUICommand {
     SetWeights(Weights),
     Toggle,
+    Pause,
+    Resume,
     Quit,
+    /// Choose the output device. None means the default output device.
+    SetOutputDevice(Option<OutputDevice>),
+    /// Set the master volume in 0..1.
+    SetVolume(f32),
+    /// Ask the daemon for its current state, which is answered with `Reply::Status`.
+    GetStatus,
+    /// Check that the daemon speaks our protocol version, which is answered with `Reply::Hello`.
+    Hello,
+    /// Receive a `Reply::Event` whenever the state of the daemon changes. Needs a bound socket.
+    Subscribe,
+    Unsubscribe,
+    /// Map the next MIDI controller that is moved to the target. Needs the daemon's MIDI input.
+    MidiLearn(MidiTarget),
+    /// Pause after the first number of seconds, fading out during the last second number of seconds before that.
+    /// Replaces a sleep timer that is already running.
+    SetSleepTimer(f32, f32),
+    CancelSleepTimer,
+    /// Start playing a slot at a point in time, fading in slowly. Replied to with `Reply::Ok`.
+    SetAlarm(Alarm),
+    /// Replied to with `Reply::Alarms`.
+    ListAlarms,
+    /// Cancel the alarm with this id.
+    CancelAlarm(u32),
+    /// Replied to with `Reply::OutputDevices`. The devices are those the daemon can play on, not the client.
+    ListOutputDevices,
+    /// Replied to with `Reply::Hosts`.
+    ListHosts,
+    /// A command of a newer client that we don't know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The daemon answers every command of a client that has a bound socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Reply {
+    Ok,
+    /// The command failed, with a human readable description of the error.
+    Error(String),
+    Status(Box<Status>),
+    /// The package version of the daemon.
+    Hello(String),
+    /// Not the answer to a command but pushed to subscribed clients when the state of the daemon changes.
+    Event(#[serde(deserialize_with = "deserialize_or_unknown")] Event),
+    /// The alarms that are set, ordered by the time they ring.
+    Alarms(Vec<Alarm>),
+    /// All output devices of all available hosts.
+    OutputDevices(Vec<OutputDeviceInfo>),
+    /// The names of the available audio hosts, e.g. ALSA.
+    Hosts(Vec<String>),
+    /// A reply of a newer daemon that we don't know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A change of the state of the daemon, no matter which client caused it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    WeightsChanged(Weights),
+    /// Whether noise is audible, see `Status::playing`.
+    PlayingChanged(bool),
+    VolumeChanged(f32),
+    OutputDeviceChanged(Option<OutputDevice>),
+    /// A sleep timer was set, started to fade out, ended or was cancelled.
+    SleepTimerChanged(Option<SleepTimerStatus>),
+    /// The audio stream failed with this error. The daemon keeps trying to play the noise on a new stream.
+    StreamLost(String),
+    /// The noise plays on a new stream again after `StreamLost`.
+    StreamRecovered,
+    /// An event of a newer daemon that we don't know.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Event {
+    /// The events that lead from the state `before` to the state `after`.
+    pub fn changes(before: &Status, after: &Status) -> Vec<Event> {
+        let mut events = Vec::new();
+        if let Some(weights) = after.weights.filter(|weights| before.weights.as_ref() != Some(weights)) {
+            events.push(Event::WeightsChanged(weights));
+        }
+        if before.playing != after.playing {
+            events.push(Event::PlayingChanged(after.playing));
+        }
+        if before.volume != after.volume {
+            events.push(Event::VolumeChanged(after.volume));
+        }
+        if before.output_device != after.output_device {
+            events.push(Event::OutputDeviceChanged(after.output_device.clone()));
+        }
+        // The remaining time counts down all the time, so only jumps are changes.
+        let sleep_timer_changed = match (&before.sleep_timer, &after.sleep_timer) {
+            (Some(a), Some(b)) => a.fading != b.fading || (a.remaining_secs - b.remaining_secs).abs() > 1.0,
+            (a, b) => a.is_some() != b.is_some(),
+        };
+        if sleep_timer_changed {
+            events.push(Event::SleepTimerChanged(after.sleep_timer.clone()));
+        }
+        events
+    }
 }
 
-const GUI_COMMAND_BUF_LEN: usize = 1024;
+/// The state of the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    /// Whether noise is audible right now, i.e. there is a stream and it is not paused.
+    pub playing: bool,
+    /// The weights of the noise that is playing, None if no weights were set yet.
+    pub weights: Option<Weights>,
+    pub volume: f32,
+    /// The output device chosen by the user, None means the default device.
+    pub output_device: Option<OutputDevice>,
+    /// The stream that is currently playing, None if there is none.
+    pub stream: Option<StreamStatus>,
+    /// How long the daemon is running.
+    pub uptime_secs: f64,
+    /// The name of the preset if the weights are one of the presets.
+    #[serde(default)]
+    pub preset: Option<String>,
+    /// The sleep timer, None if there is none.
+    #[serde(default)]
+    pub sleep_timer: Option<SleepTimerStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SleepTimerStatus {
+    /// How long until the noise is paused.
+    pub remaining_secs: f32,
+    /// How long the fade out before that takes.
+    pub fade_secs: f32,
+    /// Whether the noise is fading out already.
+    pub fading: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamStatus {
+    /// Where the stream plays, e.g. the device name.
+    pub description: String,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub buffer_frames: Option<u32>,
+    pub stats: StreamStats,
+}
+
+/// Increased for every incompatible change of the messages, see the module documentation.
+/// Version 3 encodes the messages as JSON instead of bincode.
+pub const PROTOCOL_VERSION: u16 = 3;
+/// Every message starts with these bytes so that we can tell apart messages of old clients without an envelope.
+const MAGIC: &[u8; 3] = b"ADH";
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+/// Commands are small, but JSON spells out every weight.
+const GUI_COMMAND_BUF_LEN: usize = 4096;
+/// Replies can contain a whole status or all output devices, so they get a lot more space.
+const REPLY_BUF_LEN: usize = 64 * 1024;
+/// Only protects against running out of memory on garbage, real messages are much smaller.
+const STREAM_MESSAGE_MAX_LEN: usize = 64 * 1024 * 1024;
+/// How long a client waits for the reply of the daemon.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A command received by the daemon.
+#[derive(Debug)]
+pub struct Request {
+    pub command: Result<GUICommand, anyhow::Error>,
+    /// The address of the client, None if it can't receive replies.
+    pub client: Option<SocketAddr>,
+    /// The user id of the client as checked by the kernel, None if the kernel did not tell us.
+    pub uid: Option<u32>,
+}
 
 #[derive(Debug)]
 pub struct Protocol {
@@ -19,8 +227,10 @@ pub struct Protocol {
 }
 
 impl Protocol {
-    pub fn new_raw(sock: UnixDatagram) -> Self {
-        Self { sock }
+    /// Use a socket that was created elsewhere, e.g. by systemd, to receive commands.
+    pub fn new_raw(sock: UnixDatagram) -> Result<Self, anyhow::Error> {
+        pass_credentials(&sock)?;
+        Ok(Self { sock })
     }
 
     pub fn new_recv() -> Result<Self, anyhow::Error> {
@@ -36,18 +246,45 @@ impl Protocol {
                 return Err(e.into());
             }
         };
+        // Only our user may send commands. In development mode the socket is in the world-writable /tmp/.
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+        pass_credentials(&sock)?;
 
         Ok(Self { sock })
     }
 
+    /// Create a client socket connected to the daemon.
     pub fn new_send() -> Result<Self, anyhow::Error> {
-        let sock = UnixDatagram::unbound().unwrap();
-        sock.connect(SOCKET_PATH.as_path()).unwrap();
+        let protocol = Self::new_client()?;
+        protocol.connect()?;
+        Ok(protocol)
+    }
+
+    /// Create a client socket that is not connected yet.
+    /// The socket is bound to a unique address in the abstract namespace so that the daemon can reply to it.
+    pub fn new_client() -> Result<Self, anyhow::Error> {
+        static CLIENT_COUNTER: AtomicU32 = AtomicU32::new(0);
+        let name = format!(
+            "adh-rs-client-{}-{}",
+            std::process::id(),
+            CLIENT_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let addr = SocketAddr::from_abstract_name(name)?;
+
+        let sock = UnixDatagram::bind_addr(&addr)?;
+        sock.set_read_timeout(Some(REPLY_TIMEOUT))?;
         Ok(Protocol { sock })
     }
 
+    /// Connect to the socket of the daemon.
+    /// After the daemon was restarted, connecting again is necessary to reach the new socket.
+    pub fn connect(&self) -> Result<(), anyhow::Error> {
+        self.sock.connect(SOCKET_PATH.as_path())?;
+        Ok(())
+    }
+
     pub fn send(&self, message: &GUICommand) -> Result<(), anyhow::Error> {
-        let serialized_command = bincode::serde::encode_to_vec(message, bincode::config::standard())?;
+        let serialized_command = encode_message(message)?;
         if serialized_command.len() > GUI_COMMAND_BUF_LEN {
             return Err(anyhow!("Gui Command too big to encode. Increase buffer size."));
         }
@@ -59,12 +296,427 @@ impl Protocol {
         Ok(())
     }
 
-    pub fn recv(&self) -> Result<GUICommand, anyhow::Error> {
-        let mut buf = vec![0; GUI_COMMAND_BUF_LEN];
+    /// Send a command and wait for the reply of the daemon.
+    /// Replies to earlier commands that we did not wait for are discarded first.
+    pub fn request(&self, message: &GUICommand) -> Result<Reply, anyhow::Error> {
+        self.discard_replies()?;
+        self.send(message)?;
+
+        let mut buf = vec![0; REPLY_BUF_LEN];
         let read_bytes = self.sock.recv(&mut buf)?;
+        decode_message(&buf[..read_bytes]).map_err(|e| anyhow!("Invalid reply from the daemon: {}", e))
+    }
+
+    /// Wait until the daemon pushes an event. Replies to commands are skipped.
+    /// Only useful after subscribing with `GUICommand::Subscribe`.
+    /// Returns None if no event arrived within the read timeout.
+    pub fn recv_event(&self) -> Result<Option<Event>, anyhow::Error> {
+        let mut buf = vec![0; REPLY_BUF_LEN];
+        loop {
+            let read_bytes = match self.sock.recv(&mut buf) {
+                Ok(read_bytes) => read_bytes,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            match decode_message(&buf[..read_bytes]) {
+                Ok(Reply::Event(event)) => return Ok(Some(event)),
+                Ok(_) => continue,
+                Err(e) => eprintln!("Invalid message from the daemon: {}", e),
+            }
+        }
+    }
+
+    /// Check that the daemon speaks our protocol version and return its package version.
+    pub fn handshake(&self) -> Result<String, anyhow::Error> {
+        match self.request(&GUICommand::Hello)? {
+            Reply::Hello(daemon_version) => Ok(daemon_version),
+            Reply::Error(e) => Err(anyhow!(e)),
+            reply => Err(anyhow!("Unexpected reply to handshake: {:?}", reply)),
+        }
+    }
+
+    fn discard_replies(&self) -> Result<(), anyhow::Error> {
+        let mut buf = vec![0; REPLY_BUF_LEN];
+        self.sock.set_nonblocking(true)?;
+        let result = loop {
+            match self.sock.recv(&mut buf) {
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.sock.set_nonblocking(false)?;
+        result
+    }
+
+    /// Receive a command together with the address of the client if it can receive a reply and its user id.
+    /// Only fails if the socket fails. A message that can't be decoded is returned as the error of the request.
+    pub fn recv(&self) -> Result<Request, anyhow::Error> {
+        let mut buf = vec![0; GUI_COMMAND_BUF_LEN];
+        let (read_bytes, client, uid) = recv_with_credentials(&self.sock, &mut buf)?;
+
+        let command = decode_message(&buf[..read_bytes]);
+        Ok(Request { command, client, uid })
+    }
+
+    /// Send a reply to a client.
+    /// A client that does not read its replies must not block the daemon, so if its queue is full the reply is dropped.
+    pub fn reply(&self, addr: &SocketAddr, reply: &Reply) -> Result<(), anyhow::Error> {
+        let serialized_reply = encode_message(reply)?;
+        if serialized_reply.len() > REPLY_BUF_LEN {
+            return Err(anyhow!("Reply too big to encode. Increase buffer size."));
+        }
+
+        match send_to_nonblocking(&self.sock, &serialized_reply, addr) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// A connection on the stream socket of the daemon, used by both the daemon and its clients.
+/// Unlike with `Protocol` the size of messages is not limited by a buffer.
+#[derive(Debug)]
+pub struct StreamProtocol {
+    stream: UnixStream,
+}
+
+impl StreamProtocol {
+    /// Use a connection that was accepted by the daemon.
+    pub fn new_raw(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Create the stream socket of the daemon. Like the datagram socket only our user may connect.
+    pub fn listen() -> Result<UnixListener, anyhow::Error> {
+        let socket_path = STREAM_SOCKET_PATH.as_path();
+        if socket_path.exists() {
+            fs::remove_file(socket_path)?;
+        }
+        let listener = UnixListener::bind(socket_path)?;
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+
+    /// Connect to the stream socket of the daemon.
+    pub fn connect() -> Result<Self, anyhow::Error> {
+        let stream = UnixStream::connect(STREAM_SOCKET_PATH.as_path())?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        Ok(Self { stream })
+    }
+
+    /// Send a command and wait for the reply of the daemon. Events that arrive in the meantime are skipped.
+    /// Replies arrive in the order of the commands, so every command must be sent with `request`.
+    pub fn request(&self, message: &GUICommand) -> Result<Reply, anyhow::Error> {
+        self.write_message(message)?;
+        loop {
+            let buf = self
+                .read_message()?
+                .ok_or(anyhow!("The daemon closed the connection"))?;
+            match decode_message(&buf).map_err(|e| anyhow!("Invalid reply from the daemon: {}", e))? {
+                Reply::Event(_) => continue,
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    /// Wait until the daemon pushes an event, see `Protocol::recv_event`.
+    pub fn recv_event(&self) -> Result<Option<Event>, anyhow::Error> {
+        loop {
+            let buf = match self.read_message() {
+                Ok(Some(buf)) => buf,
+                Ok(None) => return Err(anyhow!("The daemon closed the connection")),
+                Err(e) => match e.downcast_ref::<std::io::Error>().map(std::io::Error::kind) {
+                    Some(ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                    _ => return Err(e),
+                },
+            };
+            match decode_message(&buf) {
+                Ok(Reply::Event(event)) => return Ok(Some(event)),
+                Ok(_) => continue,
+                Err(e) => eprintln!("Invalid message from the daemon: {}", e),
+            }
+        }
+    }
+
+    /// Check that the daemon speaks our protocol version and return its package version.
+    pub fn handshake(&self) -> Result<String, anyhow::Error> {
+        match self.request(&GUICommand::Hello)? {
+            Reply::Hello(daemon_version) => Ok(daemon_version),
+            Reply::Error(e) => Err(anyhow!(e)),
+            reply => Err(anyhow!("Unexpected reply to handshake: {:?}", reply)),
+        }
+    }
+
+    /// Receive a command from a client, None if the client closed the connection.
+    /// A message that can't be decoded is returned as the inner error, the connection can still be used.
+    pub fn recv(&self) -> Result<Option<Result<GUICommand, anyhow::Error>>, anyhow::Error> {
+        Ok(self.read_message()?.map(|buf| decode_message(&buf)))
+    }
+
+    pub fn reply(&self, reply: &Reply) -> Result<(), anyhow::Error> {
+        self.write_message(reply)
+    }
+
+    fn write_message<T: Serialize>(&self, message: &T) -> Result<(), anyhow::Error> {
+        let buf = encode_message(message)?;
+        let len = u32::try_from(buf.len()).map_err(|_| anyhow!("Message too big to send"))?;
+        let mut frame = Vec::with_capacity(4 + buf.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&buf);
+        (&self.stream).write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Read the next length-prefixed message, None at the end of the stream.
+    fn read_message(&self) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let mut len = [0; 4];
+        match (&self.stream).read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > STREAM_MESSAGE_MAX_LEN {
+            return Err(anyhow!("Message of {} bytes is too big", len));
+        }
+        let mut buf = vec![0; len];
+        (&self.stream).read_exact(&mut buf)?;
+        Ok(Some(buf))
+    }
+}
+
+fn encode_message<T: Serialize>(message: &T) -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf = Vec::with_capacity(HEADER_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    serde_json::to_writer(&mut buf, message)?;
+    Ok(buf)
+}
+
+/// Check the envelope and decode the message in it.
+fn decode_message<T: DeserializeOwned>(buf: &[u8]) -> Result<T, anyhow::Error> {
+    if buf.len() < HEADER_LEN || &buf[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!(
+            "Message without protocol version, the peer is too old. Expected version {}.",
+            PROTOCOL_VERSION
+        ));
+    }
+    let version = u16::from_le_bytes([buf[MAGIC.len()], buf[MAGIC.len() + 1]]);
+    if version != PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "Protocol version mismatch: got version {} but expected version {}.",
+            version,
+            PROTOCOL_VERSION
+        ));
+    }
+
+    decode_json(&buf[HEADER_LEN..]).map_err(|e| anyhow!("Malformed message: {}", e))
+}
+
+/// Decode a message, turning unknown variants into the `Unknown` variant of the enum.
+pub fn decode_json<T: DeserializeOwned>(json: &[u8]) -> Result<T, serde_json::Error> {
+    from_value_or_unknown(serde_json::from_slice(json)?)
+}
+
+/// `#[serde(other)]` only catches unknown variants without content, like `"NewCommand"`, so for
+/// `{"NewCommand": ...}` we try again without the content. Known variants fail that second try, so their real
+/// errors are kept.
+fn from_value_or_unknown<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, serde_json::Error> {
+    match value {
+        serde_json::Value::Object(object) if object.len() == 1 => {
+            serde_json::from_value(serde_json::Value::Object(object.clone())).or_else(|e| {
+                let variant = object.keys().next().cloned().unwrap_or_default();
+                serde_json::from_value(serde_json::Value::String(variant)).map_err(|_| e)
+            })
+        }
+        value => serde_json::from_value(value),
+    }
+}
+
+/// Like `decode_json` for enums inside of messages, e.g. the event of `Reply::Event`.
+fn deserialize_or_unknown<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    from_value_or_unknown(serde_json::Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// `UnixDatagram::send_to_addr` with `MSG_DONTWAIT`, which std does not expose.
+fn send_to_nonblocking(sock: &UnixDatagram, buf: &[u8], addr: &SocketAddr) -> std::io::Result<()> {
+    // Build the raw address. Abstract names start with a null byte, paths end with one.
+    let mut raw: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    raw.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let (name, prefix, suffix) = match (addr.as_abstract_name(), addr.as_pathname()) {
+        (Some(name), _) => (name, 1, 0),
+        (None, Some(path)) => (path.as_os_str().as_bytes(), 0, 1),
+        (None, None) => return Err(std::io::Error::new(ErrorKind::InvalidInput, "Unnamed address")),
+    };
+    if prefix + name.len() + suffix > raw.sun_path.len() {
+        return Err(std::io::Error::new(ErrorKind::InvalidInput, "Address too long"));
+    }
+    for (dst, src) in raw.sun_path[prefix..].iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    let len = std::mem::offset_of!(libc::sockaddr_un, sun_path) + prefix + name.len() + suffix;
+
+    let sent = unsafe {
+        libc::sendto(
+            sock.as_raw_fd(),
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+            &raw as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Ask the kernel to attach the credentials of the sender to every datagram we receive.
+fn pass_credentials(sock: &UnixDatagram) -> std::io::Result<()> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `UnixDatagram::recv_from` that also returns the user id from the `SCM_CREDENTIALS` message, which std does not expose.
+/// Unbound clients have an unnamed address, so we can't reply to them and None is returned as the address.
+fn recv_with_credentials(
+    sock: &UnixDatagram,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, Option<SocketAddr>, Option<u32>)> {
+    let mut raw: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Enough space for the credentials message, u64 to get the alignment of a cmsghdr.
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut raw as *mut libc::sockaddr_un as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let read_bytes = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if read_bytes < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut uid = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS {
+                let credentials = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+                uid = Some(credentials.uid);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    // The reverse of `send_to_nonblocking`: abstract names start with a null byte, paths end with one.
+    let name_len = (msg.msg_namelen as usize).saturating_sub(std::mem::offset_of!(libc::sockaddr_un, sun_path));
+    let name: Vec<u8> = raw.sun_path[..name_len].iter().map(|c| *c as u8).collect();
+    let client = match name.split_first() {
+        None => None,
+        Some((0, abstract_name)) => Some(SocketAddr::from_abstract_name(abstract_name)?),
+        Some(_) => {
+            let path = name.split(|c| *c == 0).next().unwrap_or_default();
+            Some(SocketAddr::from_pathname(OsStr::from_bytes(path))?)
+        }
+    };
+    Ok((read_bytes as usize, client, uid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(json: &str) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        buf.extend_from_slice(json.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = encode_message(&GUICommand::SetSleepTimer(60.0, 5.0)).unwrap();
+        assert!(matches!(
+            decode_message(&buf).unwrap(),
+            GUICommand::SetSleepTimer(duration, fade) if duration == 60.0 && fade == 5.0
+        ));
+    }
+
+    #[test]
+    fn extra_fields_are_ignored_and_new_fields_default() {
+        let json = r#"{"remaining_secs": 10.0, "fade_secs": 5.0, "fading": true, "from_the_future": [1, 2]}"#;
+        let reply: Reply =
+            decode_message(&envelope(&format!(r#"{{"Event": {{"SleepTimerChanged": {}}}}}"#, json))).unwrap();
+        assert!(matches!(
+            reply,
+            Reply::Event(Event::SleepTimerChanged(Some(SleepTimerStatus { fading: true, .. })))
+        ));
+
+        // A status of a daemon from before `preset` and `sleep_timer`.
+        let status = r#"{"Status": {"playing": true, "weights": null, "volume": 0.5, "output_device": null,
+            "stream": null, "uptime_secs": 3.0}}"#;
+        let Reply::Status(status) = decode_message(&envelope(status)).unwrap() else {
+            panic!("Expected a status");
+        };
+        assert!(status.playing);
+        assert_eq!(status.preset, None);
+        assert!(status.sleep_timer.is_none());
+    }
+
+    #[test]
+    fn unknown_variants_decode_as_unknown() {
+        assert!(matches!(
+            decode_message(&envelope(r#""Dance""#)).unwrap(),
+            GUICommand::Unknown
+        ));
+        assert!(matches!(
+            decode_message(&envelope(r#"{"Dance": {"steps": 3}}"#)).unwrap(),
+            GUICommand::Unknown
+        ));
+        assert!(matches!(
+            decode_message(&envelope(r#"{"Event": {"Earthquake": 7.5}}"#)).unwrap(),
+            Reply::Event(Event::Unknown)
+        ));
+    }
+
+    #[test]
+    fn malformed_known_variants_are_errors() {
+        assert!(decode_message::<GUICommand>(&envelope(r#"{"SetVolume": "loud"}"#)).is_err());
+        assert!(decode_message::<GUICommand>(&envelope(r#"{"SetVolume": 0.5, "Toggle": null}"#)).is_err());
+        assert!(decode_message::<GUICommand>(&envelope("not json")).is_err());
+    }
 
-        let (command, _): (GUICommand, usize) =
-            bincode::serde::decode_from_slice(&buf[..read_bytes], bincode::config::standard())?;
-        Ok(command)
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut buf = envelope(r#""Toggle""#);
+        buf[MAGIC.len()] = buf[MAGIC.len()].wrapping_add(1);
+        assert!(decode_message::<GUICommand>(&buf).is_err());
+        assert!(decode_message::<GUICommand>(br#""Toggle""#).is_err());
     }
 }
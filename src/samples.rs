@@ -12,9 +12,17 @@
 
 use anyhow::anyhow;
 use lerp::Lerp;
-use std::f32;
+use std::{
+    f32,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
 
-pub const CHUNK_SAMPLES: usize = 44_100 * 3;
+use crate::wav::{self, WavFormat, WavWriter};
+
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const CHUNK_SAMPLES: usize = SAMPLE_RATE as usize * 3;
 const BLEND_WINDOW: usize = 1000;
 
 pub type RawSample = [f32; CHUNK_SAMPLES];
@@ -44,6 +52,48 @@ impl Sample {
     pub fn get(&self, idx: usize) -> Option<&f32> {
         self.data.get(idx)
     }
+
+    /// Write the sample as a mono WAV file, e.g. to inspect it in Audacity.
+    pub fn write_wav(&self, path: &Path, format: WavFormat) -> Result<(), anyhow::Error> {
+        let f = BufWriter::new(File::create(path)?);
+        let mut writer = WavWriter::new(f, format, 1, SAMPLE_RATE)?;
+        writer.write_samples(&self.data[..])?;
+        writer.finalize()?;
+        Ok(())
+    }
+
+    /// Read a WAV file and cut it into chunks that can be used for `BlendingSamples`.
+    /// Multiple channels are mixed down to mono.
+    /// The file is treated as a loop, i.e. if the last chunk is not full, it continues from the start of the file.
+    pub fn read_wav(path: &Path) -> Result<Vec<Sample>, anyhow::Error> {
+        let wav = wav::read_wav(BufReader::new(File::open(path)?))?;
+        if wav.sample_rate != SAMPLE_RATE {
+            return Err(anyhow!(
+                "Unsupported sample rate {}, only {} is supported",
+                wav.sample_rate,
+                SAMPLE_RATE
+            ));
+        }
+
+        let data = wav.into_mono();
+        if data.is_empty() {
+            return Err(anyhow!("WAV file is empty"));
+        }
+
+        let chunks_num = data.len().div_ceil(CHUNK_SAMPLES);
+        (0..chunks_num)
+            .map(|i| {
+                let chunk = data
+                    .iter()
+                    .cycle()
+                    .skip(i * CHUNK_SAMPLES)
+                    .take(CHUNK_SAMPLES)
+                    .copied()
+                    .collect();
+                Sample::new(chunk)
+            })
+            .collect()
+    }
 }
 
 impl IntoIterator for Sample {
@@ -139,7 +189,8 @@ pub struct BlendingSamples {
     smoothing_type: SmoothingType,
 }
 
-type StereoSampleIter = Box<dyn Iterator<Item = (f32, f32)> + Send>;
+/// An infinite stream of mono samples.
+pub type MonoSampleIter = Box<dyn Iterator<Item = f32> + Send>;
 
 struct BlendingSamplesIterator<I, J> {
     chunk_iter: I,
@@ -169,7 +220,7 @@ impl BlendingSamples {
         self
     }
 
-    pub fn into_iter(self) -> Result<StereoSampleIter, anyhow::Error> {
+    pub fn into_iter(self) -> Result<MonoSampleIter, anyhow::Error> {
         match self.smoothing_type {
             SmoothingType::Mirror => {
                 let first_chunk = self.samples.into_iter().next().unwrap();
@@ -178,7 +229,6 @@ impl BlendingSamples {
                     .clone()
                     .into_iter()
                     .chain(first_chunk.into_iter().rev())
-                    .map(|f| (f, f))
                     .cycle();
 
                 Ok(Box::new(iter))
@@ -208,7 +258,7 @@ impl<I: Iterator<Item = J>, J: IntoIterator<IntoIter = K>, K> BlendingSamplesIte
 impl<I: Iterator<Item = J>, J: IntoIterator<Item = f32, IntoIter = K>, K: Iterator<Item = f32> + ExactSizeIterator>
     Iterator for BlendingSamplesIterator<I, K>
 {
-    type Item = (f32, f32);
+    type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
         let s = if self.current_chunk.len() <= BLEND_WINDOW {
@@ -229,6 +279,6 @@ impl<I: Iterator<Item = J>, J: IntoIterator<Item = f32, IntoIter = K>, K: Iterat
             self.current_chunk.next().unwrap()
         };
 
-        Some((s, s))
+        Some(s)
     }
 }
@@ -0,0 +1,638 @@
+//! Module for the outputs that the daemon can play noise on.
+//!
+//! Normally we play on a sound card via cpal (see `audio_bridge`). For testing the daemon on machines without
+//! a sound card there are also offline sinks which write the noise into a WAV file, write raw PCM to stdout,
+//! or just count the frames and throw them away.
+//! Offline sinks pull the samples in real time so that fades and timers behave the same as with a sound card.
+
+use anyhow::anyhow;
+use cpal::SampleFormat;
+use serde::{Deserialize, Serialize};
+use std::{
+    any::Any,
+    fs::File,
+    io::{BufWriter, Write},
+    os::fd::FromRawFd,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio_bridge::{write_data, CpalSink, OutputDevice},
+    channels::ChannelSamples,
+    config::Config,
+    dither::{Dither, DitherMode},
+    gain::{GainControl, GainStage},
+    samples::SAMPLE_RATE,
+    wav::{WavFormat, WavWriter},
+};
+
+/// Offline sinks always play stereo.
+const OFFLINE_CHANNELS: usize = 2;
+/// How many frames offline sinks produce at once if no buffer size is configured.
+const OFFLINE_BLOCK_FRAMES: u32 = 1024;
+
+/// Samples shared between the daemon and the stream, so that they survive rebuilding the stream.
+pub type SharedSamples = Arc<Mutex<ChannelSamples>>;
+/// Creates the samples for a stream with the given number of channels.
+pub type MakeSamples<'a> = &'a dyn Fn(usize) -> Result<ChannelSamples, anyhow::Error>;
+/// Called with a description of the error when a running stream fails.
+pub type ErrorHandler = Arc<dyn Fn(String) + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    /// Human readable description of where the stream plays, e.g. the device name.
+    pub description: String,
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// The buffer size in frames that we requested. None means the default of the device.
+    pub buffer_frames: Option<u32>,
+}
+
+/// Counters that the stream updates while it is playing.
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    frames: AtomicU64,
+    underruns: AtomicU64,
+    callback_frames: AtomicU64,
+    latency_us: AtomicU64,
+}
+
+impl StreamCounters {
+    /// Record that the stream asked for `frames` frames which will be played after `latency`.
+    pub fn record_callback(&self, frames: usize, latency: Duration) {
+        self.frames.fetch_add(frames as u64, Ordering::Relaxed);
+        self.callback_frames.store(frames as u64, Ordering::Relaxed);
+        self.latency_us.store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Record that the stream could not be supplied with samples in time.
+    pub fn record_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        StreamStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            underruns: self.underruns.load(Ordering::Relaxed),
+            callback_frames: self.callback_frames.load(Ordering::Relaxed),
+            latency_ms: self.latency_us.load(Ordering::Relaxed) as f32 / 1000.0,
+        }
+    }
+}
+
+/// Statistics of a running stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct StreamStats {
+    /// How many frames the stream has played so far.
+    pub frames: u64,
+    /// How often the stream ran out of samples, e.g. because the callback was called too late.
+    pub underruns: u64,
+    /// How many frames the stream asked for in the last callback, i.e. the actual buffer size.
+    pub callback_frames: u64,
+    /// How long it takes from producing a sample until it is played.
+    pub latency_ms: f32,
+}
+
+/// A running stream. Dropping it stops playback.
+pub struct AudioStream {
+    /// The generator state of the stream. Can be used to continue playing on another stream.
+    pub samples: SharedSamples,
+    pub info: StreamInfo,
+    counters: Arc<StreamCounters>,
+    /// Keeps the stream alive, e.g. the cpal stream or the thread of an offline sink.
+    _handle: Box<dyn Any>,
+}
+
+impl AudioStream {
+    pub fn new(samples: SharedSamples, info: StreamInfo, counters: Arc<StreamCounters>, handle: impl Any) -> Self {
+        Self {
+            samples,
+            info,
+            counters,
+            _handle: Box::new(handle),
+        }
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.counters.stats()
+    }
+}
+
+/// The buffer size that the user asked for in the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BufferRequest {
+    pub frames: Option<u32>,
+    pub latency: Option<Duration>,
+}
+
+impl BufferRequest {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            frames: config.buffer_frames,
+            latency: config
+                .latency_ms
+                .and_then(|ms| Duration::try_from_secs_f32(ms / 1000.0).ok()),
+        }
+    }
+
+    /// The requested buffer size in frames for a stream with `sample_rate`.
+    /// An explicit number of frames takes precedence over the latency.
+    pub fn frames(&self, sample_rate: u32) -> Option<u32> {
+        self.frames.or_else(|| {
+            self.latency
+                .map(|latency| (latency.as_secs_f64() * sample_rate as f64).round().max(1.0) as u32)
+        })
+    }
+}
+
+pub trait AudioSink {
+    /// How many channels a stream on `device` would have, so that we can generate the right number of sources.
+    fn channels(&self, device: Option<&OutputDevice>) -> Result<usize, anyhow::Error>;
+
+    /// Start playing `samples` on `device`, or the default device if it is None.
+    /// If the stream ends up with another number of channels than the samples were made for, e.g. because it fell
+    /// back on another config or the device changed, the samples are replaced by new ones from `make_samples`.
+    /// The volume of the stream is controlled by `gain`.
+    /// Errors that occur while the stream is playing are passed to `on_error`.
+    fn play_samples(
+        &self,
+        device: Option<&OutputDevice>,
+        samples: SharedSamples,
+        make_samples: MakeSamples,
+        gain: GainControl,
+        on_error: ErrorHandler,
+    ) -> Result<AudioStream, anyhow::Error>;
+
+    /// Called when the daemon quits after its last stream stopped, e.g. to finalize a file.
+    fn finish(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Start playing on `sink`, with samples created by `make_samples` once we know how many channels the stream has.
+pub fn play(
+    sink: &dyn AudioSink,
+    device: Option<&OutputDevice>,
+    make_samples: MakeSamples,
+    gain: GainControl,
+    on_error: ErrorHandler,
+) -> Result<AudioStream, anyhow::Error> {
+    let channels = sink.channels(device)?;
+    let samples = Arc::new(Mutex::new(make_samples(channels)?));
+    sink.play_samples(device, samples, make_samples, gain, on_error)
+}
+
+/// Replace `samples` by new ones from `make_samples` unless they were made for `channels` channels.
+pub(crate) fn fit_samples(
+    samples: &SharedSamples,
+    channels: usize,
+    make_samples: MakeSamples,
+) -> Result<(), anyhow::Error> {
+    let mut samples = samples.lock().unwrap();
+    if !samples.fits(channels) {
+        *samples = make_samples(channels)?;
+    }
+    Ok(())
+}
+
+/// The sinks that can be chosen on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkKind {
+    /// Play on a sound card.
+    Cpal,
+    /// Write 32-bit float samples into a WAV file.
+    Wav(PathBuf),
+    /// Write raw 32-bit float little-endian samples to stdout.
+    Stdout,
+    /// Count the frames and throw them away.
+    Null,
+}
+
+impl FromStr for SinkKind {
+    type Err = anyhow::Error;
+
+    /// Parses `cpal`, `wav:<path>`, `stdout` or `null`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpal" => Ok(SinkKind::Cpal),
+            "stdout" => Ok(SinkKind::Stdout),
+            "null" => Ok(SinkKind::Null),
+            _ => match s.strip_prefix("wav:") {
+                Some(path) if !path.is_empty() => Ok(SinkKind::Wav(PathBuf::from(path))),
+                _ => Err(anyhow!("Unknown sink {}, expected cpal, wav:<path>, stdout or null", s)),
+            },
+        }
+    }
+}
+
+impl SinkKind {
+    pub fn open(&self, config: &Config) -> Result<Box<dyn AudioSink>, anyhow::Error> {
+        let buffer = BufferRequest::from_config(config);
+        let sink: Box<dyn AudioSink> = match self {
+            SinkKind::Cpal => Box::new(CpalSink::new(buffer, config.dither)),
+            SinkKind::Wav(path) => Box::new(WavSink::create(path.clone(), buffer)?),
+            SinkKind::Stdout => Box::new(StdoutSink::new(buffer)?),
+            SinkKind::Null => Box::new(NullSink::new(buffer)),
+        };
+        Ok(sink)
+    }
+}
+
+/// Destination of the samples of an offline sink.
+trait OfflineWriter: Send + 'static {
+    fn write(&mut self, buf: &[f32]) -> Result<(), anyhow::Error>;
+}
+
+/// The writer is shared between all streams of a sink, so that a file is not overwritten when the stream is rebuilt.
+type SharedWriter = Arc<Mutex<dyn OfflineWriter>>;
+
+/// Thread that pulls the samples of an offline sink in real time.
+struct OfflineStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for OfflineStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn play_offline(
+    description: &str,
+    buffer: BufferRequest,
+    writer: SharedWriter,
+    samples: SharedSamples,
+    make_samples: MakeSamples,
+    gain: GainControl,
+    on_error: ErrorHandler,
+) -> Result<AudioStream, anyhow::Error> {
+    fit_samples(&samples, OFFLINE_CHANNELS, make_samples)?;
+    let block_frames = buffer.frames(SAMPLE_RATE).unwrap_or(OFFLINE_BLOCK_FRAMES) as usize;
+    let block_duration = Duration::from_secs_f64(block_frames as f64 / SAMPLE_RATE as f64);
+    let info = StreamInfo {
+        description: description.to_owned(),
+        sample_rate: SAMPLE_RATE,
+        channels: OFFLINE_CHANNELS,
+        buffer_frames: Some(block_frames as u32),
+    };
+    let counters = Arc::new(StreamCounters::default());
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = thread::spawn({
+        let samples = samples.clone();
+        let counters = counters.clone();
+        let stop = stop.clone();
+
+        move || {
+            let mut buf = vec![0.0; block_frames * OFFLINE_CHANNELS];
+            let mut frame_buf = vec![0.0; OFFLINE_CHANNELS];
+            let mut gain = GainStage::new(gain, SAMPLE_RATE);
+            // Offline sinks write floats, so there is nothing to dither.
+            let mut dither = Dither::new(DitherMode::Off, SampleFormat::F32, OFFLINE_CHANNELS);
+            let mut due = Instant::now();
+
+            while !stop.load(Ordering::Relaxed) {
+                if !write_data(
+                    &mut buf,
+                    &mut frame_buf,
+                    &mut samples.lock().unwrap(),
+                    &mut gain,
+                    &mut dither,
+                ) {
+                    counters.record_underrun();
+                }
+                if let Err(e) = writer.lock().unwrap().write(&buf) {
+                    on_error(e.to_string());
+                    return;
+                }
+                counters.record_callback(block_frames, block_duration);
+
+                // Wait until the block would have been played.
+                // If we woke up more than a block too late, a sound card would have run out of samples.
+                due += block_duration;
+                let now = Instant::now();
+                if now > due + block_duration {
+                    counters.record_underrun();
+                    due = now;
+                }
+                thread::sleep(due.saturating_duration_since(now));
+            }
+        }
+    });
+
+    let handle = OfflineStream {
+        stop,
+        thread: Some(thread),
+    };
+    Ok(AudioStream::new(samples, info, counters, handle))
+}
+
+/// Writes the noise into a WAV file. The header is finalized by `finish`, or when the sink is dropped.
+/// When the file is full, it is finalized and the stream fails.
+pub struct WavSink {
+    path: PathBuf,
+    buffer: BufferRequest,
+    writer: Arc<Mutex<WavFileWriter>>,
+}
+
+struct WavFileWriter(Option<WavWriter<BufWriter<File>>>);
+
+impl OfflineWriter for WavFileWriter {
+    fn write(&mut self, buf: &[f32]) -> Result<(), anyhow::Error> {
+        let Some(writer) = &mut self.0 else {
+            return Err(anyhow!("WAV file already finalized"));
+        };
+        let result = writer.write_samples(buf);
+        if result.is_err() {
+            // Keep what we have readable.
+            self.finalize()?;
+        }
+        result
+    }
+}
+
+impl WavFileWriter {
+    fn finalize(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(writer) = self.0.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+impl WavSink {
+    pub fn create(path: PathBuf, buffer: BufferRequest) -> Result<Self, anyhow::Error> {
+        let f = BufWriter::new(File::create(&path)?);
+        let writer = WavWriter::new(f, WavFormat::Float32, OFFLINE_CHANNELS as u16, SAMPLE_RATE)?;
+        Ok(Self {
+            path,
+            buffer,
+            writer: Arc::new(Mutex::new(WavFileWriter(Some(writer)))),
+        })
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Finalizing WAV file failed: {}", e);
+        }
+    }
+}
+
+impl AudioSink for WavSink {
+    fn channels(&self, _device: Option<&OutputDevice>) -> Result<usize, anyhow::Error> {
+        Ok(OFFLINE_CHANNELS)
+    }
+
+    fn play_samples(
+        &self,
+        _device: Option<&OutputDevice>,
+        samples: SharedSamples,
+        make_samples: MakeSamples,
+        gain: GainControl,
+        on_error: ErrorHandler,
+    ) -> Result<AudioStream, anyhow::Error> {
+        let description = format!("WAV file {}", self.path.display());
+        play_offline(
+            &description,
+            self.buffer,
+            self.writer.clone(),
+            samples,
+            make_samples,
+            gain,
+            on_error,
+        )
+    }
+
+    fn finish(&self) -> Result<(), anyhow::Error> {
+        self.writer.lock().unwrap().finalize()
+    }
+}
+
+/// Writes raw 32-bit float little-endian samples to stdout, e.g. to pipe them into `aplay` or `sox`.
+pub struct StdoutSink {
+    buffer: BufferRequest,
+    writer: Arc<Mutex<StdoutWriter>>,
+}
+
+struct StdoutWriter(BufWriter<File>);
+
+impl OfflineWriter for StdoutWriter {
+    fn write(&mut self, buf: &[f32]) -> Result<(), anyhow::Error> {
+        for s in buf {
+            self.0.write_all(&s.to_le_bytes())?;
+        }
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+impl StdoutSink {
+    /// Everything we print would end up in the PCM data, so we keep the original stdout for the samples
+    /// and point stdout to stderr for our messages.
+    pub fn new(buffer: BufferRequest) -> Result<Self, anyhow::Error> {
+        let pcm = unsafe {
+            let fd = libc::dup(libc::STDOUT_FILENO);
+            if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            File::from_raw_fd(fd)
+        };
+
+        Ok(Self {
+            buffer,
+            writer: Arc::new(Mutex::new(StdoutWriter(BufWriter::new(pcm)))),
+        })
+    }
+}
+
+impl AudioSink for StdoutSink {
+    fn channels(&self, _device: Option<&OutputDevice>) -> Result<usize, anyhow::Error> {
+        Ok(OFFLINE_CHANNELS)
+    }
+
+    fn play_samples(
+        &self,
+        _device: Option<&OutputDevice>,
+        samples: SharedSamples,
+        make_samples: MakeSamples,
+        gain: GainControl,
+        on_error: ErrorHandler,
+    ) -> Result<AudioStream, anyhow::Error> {
+        play_offline(
+            "stdout",
+            self.buffer,
+            self.writer.clone(),
+            samples,
+            make_samples,
+            gain,
+            on_error,
+        )
+    }
+}
+
+/// Throws the noise away. The played frames are still counted by the stream.
+pub struct NullSink {
+    buffer: BufferRequest,
+}
+
+impl NullSink {
+    pub fn new(buffer: BufferRequest) -> Self {
+        Self { buffer }
+    }
+}
+
+struct NullWriter;
+
+impl OfflineWriter for NullWriter {
+    fn write(&mut self, _buf: &[f32]) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+impl AudioSink for NullSink {
+    fn channels(&self, _device: Option<&OutputDevice>) -> Result<usize, anyhow::Error> {
+        Ok(OFFLINE_CHANNELS)
+    }
+
+    fn play_samples(
+        &self,
+        _device: Option<&OutputDevice>,
+        samples: SharedSamples,
+        make_samples: MakeSamples,
+        gain: GainControl,
+        on_error: ErrorHandler,
+    ) -> Result<AudioStream, anyhow::Error> {
+        play_offline(
+            "null",
+            self.buffer,
+            Arc::new(Mutex::new(NullWriter)),
+            samples,
+            make_samples,
+            gain,
+            on_error,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channels::ChannelMap, generator::gen_channel_samples, wav::read_wav, Weights};
+
+    fn make_samples(map: ChannelMap) -> impl Fn(usize) -> Result<ChannelSamples, anyhow::Error> {
+        move |channels| gen_channel_samples(&Weights::default(), &map, false, channels)
+    }
+
+    fn panic_on_error() -> ErrorHandler {
+        Arc::new(|error| panic!("The stream failed: {}", error))
+    }
+
+    fn playing_gain() -> GainControl {
+        let gain = GainControl::new(1.0);
+        gain.resume(Duration::ZERO);
+        gain
+    }
+
+    #[test]
+    fn null_sink_pulls_frames_in_real_time() {
+        let sink = NullSink::new(BufferRequest::default());
+        let stream = play(
+            &sink,
+            None,
+            &make_samples(ChannelMap::Stereo),
+            playing_gain(),
+            panic_on_error(),
+        )
+        .unwrap();
+        assert_eq!(stream.info.channels, OFFLINE_CHANNELS);
+        assert_eq!(stream.info.buffer_frames, Some(OFFLINE_BLOCK_FRAMES));
+
+        thread::sleep(Duration::from_millis(200));
+        let frames = stream.stats().frames;
+        // About 8800 frames, but slow test machines may be late.
+        assert!(frames >= OFFLINE_BLOCK_FRAMES as u64, "{} frames", frames);
+        assert!(frames < SAMPLE_RATE as u64, "{} frames", frames);
+    }
+
+    #[test]
+    fn wav_sink_writes_a_complete_file() {
+        let path = std::env::temp_dir().join(format!("adh-rs-test-{}.wav", std::process::id()));
+        let sink = WavSink::create(path.clone(), BufferRequest::default()).unwrap();
+        let stream = play(
+            &sink,
+            None,
+            &make_samples(ChannelMap::Stereo),
+            playing_gain(),
+            panic_on_error(),
+        )
+        .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let frames = stream.stats().frames;
+        drop(stream);
+        sink.finish().unwrap();
+        // Finishing twice, e.g. on quit and when the sink is dropped, does not break the file.
+        drop(sink);
+
+        let wav = read_wav(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(wav.channels, OFFLINE_CHANNELS as u16);
+        assert_eq!(wav.sample_rate, SAMPLE_RATE);
+        assert_eq!(wav.samples.len() as u64, frames * OFFLINE_CHANNELS as u64);
+        assert!(wav.samples.iter().any(|s| *s != 0.0));
+    }
+
+    #[test]
+    fn samples_are_made_again_for_another_channel_count() {
+        let make_samples = make_samples(ChannelMap::Independent);
+        let samples = Arc::new(Mutex::new(make_samples(6).unwrap()));
+        assert!(samples.lock().unwrap().fits(6));
+
+        let sink = NullSink::new(BufferRequest::default());
+        let stream = sink
+            .play_samples(None, samples.clone(), &make_samples, playing_gain(), panic_on_error())
+            .unwrap();
+        drop(stream);
+        assert!(samples.lock().unwrap().fits(OFFLINE_CHANNELS));
+        assert!(!samples.lock().unwrap().fits(6));
+    }
+
+    #[test]
+    fn sink_kinds() {
+        assert_eq!("cpal".parse::<SinkKind>().unwrap(), SinkKind::Cpal);
+        assert_eq!("null".parse::<SinkKind>().unwrap(), SinkKind::Null);
+        assert_eq!(
+            "wav:/tmp/noise.wav".parse::<SinkKind>().unwrap(),
+            SinkKind::Wav(PathBuf::from("/tmp/noise.wav"))
+        );
+        assert!("wav:".parse::<SinkKind>().is_err());
+        assert!("alsa".parse::<SinkKind>().is_err());
+    }
+
+    #[test]
+    fn buffer_frames_win_over_latency() {
+        let latency = BufferRequest {
+            frames: None,
+            latency: Some(Duration::from_millis(10)),
+        };
+        assert_eq!(latency.frames(48_000), Some(480));
+        let both = BufferRequest {
+            frames: Some(256),
+            ..latency
+        };
+        assert_eq!(both.frames(48_000), Some(256));
+        assert_eq!(BufferRequest::default().frames(48_000), None);
+    }
+}
@@ -14,7 +14,7 @@ use xdg::BaseDirectories;
 
 use crate::Weights;
 
-const SLOTS_NUM: usize = 10;
+pub const SLOTS_NUM: usize = 10;
 const SLOTS_FILENAME: &str = "slots.txt";
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
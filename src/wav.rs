@@ -0,0 +1,294 @@
+//! Module to read and write WAV files.
+//!
+//! We only support the two formats that are useful for us:
+//! 32-bit float (what we generate internally) and 16-bit PCM (what most other programs produce).
+//! Samples are always handled as f32 in -1..1 and converted when reading/writing.
+
+use anyhow::anyhow;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// Size of the RIFF header plus the fmt and data chunk headers that we write.
+const HEADER_LEN: u32 = 44;
+/// The sizes in the header are u32, so the data of a file can't grow beyond this (about 4 GiB).
+const MAX_DATA_LEN: u32 = u32::MAX - (HEADER_LEN - 8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavFormat {
+    Float32,
+    Pcm16,
+}
+
+impl WavFormat {
+    fn format_tag(self) -> u16 {
+        match self {
+            WavFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+            WavFormat::Pcm16 => WAVE_FORMAT_PCM,
+        }
+    }
+
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            WavFormat::Float32 => 4,
+            WavFormat::Pcm16 => 2,
+        }
+    }
+}
+
+/// Writes interleaved samples into a WAV file.
+/// The sizes in the header are only known at the end, so they are patched in `finalize`.
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    format: WavFormat,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut inner: W, format: WavFormat, channels: u16, sample_rate: u32) -> Result<Self, anyhow::Error> {
+        let block_align = channels * format.bytes_per_sample();
+
+        inner.write_all(b"RIFF")?;
+        inner.write_all(&(HEADER_LEN - 8).to_le_bytes())?;
+        inner.write_all(b"WAVE")?;
+
+        inner.write_all(b"fmt ")?;
+        inner.write_all(&16u32.to_le_bytes())?;
+        inner.write_all(&format.format_tag().to_le_bytes())?;
+        inner.write_all(&channels.to_le_bytes())?;
+        inner.write_all(&sample_rate.to_le_bytes())?;
+        inner.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        inner.write_all(&block_align.to_le_bytes())?;
+        inner.write_all(&(format.bytes_per_sample() * 8).to_le_bytes())?;
+
+        inner.write_all(b"data")?;
+        inner.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            inner,
+            format,
+            data_len: 0,
+        })
+    }
+
+    /// Write interleaved samples. Values outside of -1..1 are clipped for integer formats.
+    /// Fails without writing anything if the samples do not fit into the file anymore.
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+        let data_len = u32::try_from(samples.len() * self.format.bytes_per_sample() as usize)
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|data_len| *data_len <= MAX_DATA_LEN)
+            .ok_or(anyhow!("The WAV file reached the size limit of 4 GiB"))?;
+
+        for s in samples {
+            match self.format {
+                WavFormat::Float32 => self.inner.write_all(&s.to_le_bytes())?,
+                WavFormat::Pcm16 => {
+                    let s = (s.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                    self.inner.write_all(&s.to_le_bytes())?
+                }
+            }
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    /// Patch the chunk sizes in the header and return the inner writer.
+    pub fn finalize(mut self) -> Result<W, anyhow::Error> {
+        self.inner.seek(SeekFrom::Start(4))?;
+        self.inner.write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(HEADER_LEN as u64 - 4))?;
+        self.inner.write_all(&self.data_len.to_le_bytes())?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// The decoded content of a WAV file.
+#[derive(Debug, Clone)]
+pub struct WavData {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved samples in -1..1.
+    pub samples: Vec<f32>,
+}
+
+impl WavData {
+    /// Average all channels into a single channel.
+    pub fn into_mono(self) -> Vec<f32> {
+        let channels = self.channels as usize;
+        if channels == 1 {
+            return self.samples;
+        }
+
+        self.samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect()
+    }
+}
+
+/// Read a 32-bit float or 16-bit PCM WAV file.
+/// Unknown chunks (e.g. metadata written by Audacity) are skipped.
+pub fn read_wav<R: Read>(mut r: R) -> Result<WavData, anyhow::Error> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf)?;
+
+    if buf.len() < 12 || &buf[0..4] != b"RIFF" || &buf[8..12] != b"WAVE" {
+        return Err(anyhow!("Not a WAV file"));
+    }
+
+    let mut fmt = None;
+    let mut data = None;
+    let mut pos = 12;
+    while pos + 8 <= buf.len() {
+        let id = &buf[pos..pos + 4];
+        let len = u32::from_le_bytes(buf[pos + 4..pos + 8].try_into()?) as usize;
+        let body = buf.get(pos + 8..pos + 8 + len).ok_or(anyhow!("Truncated WAV chunk"))?;
+
+        match id {
+            b"fmt " => fmt = Some(body),
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        pos += 8 + len + (len & 1);
+    }
+
+    let fmt = fmt.ok_or(anyhow!("WAV file has no fmt chunk"))?;
+    let data = data.ok_or(anyhow!("WAV file has no data chunk"))?;
+    if fmt.len() < 16 {
+        return Err(anyhow!("WAV fmt chunk too short"));
+    }
+
+    let le_u16 = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+    let mut format_tag = le_u16(0);
+    let channels = le_u16(2);
+    let sample_rate = u32::from_le_bytes(fmt[4..8].try_into()?);
+    let bits_per_sample = le_u16(14);
+    // For the extensible format, the actual format tag is at the start of the sub format GUID.
+    if format_tag == WAVE_FORMAT_EXTENSIBLE && fmt.len() >= 26 {
+        format_tag = le_u16(24);
+    }
+
+    if channels == 0 {
+        return Err(anyhow!("WAV file has no channels"));
+    }
+
+    let samples = match (format_tag, bits_per_sample) {
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        (WAVE_FORMAT_PCM, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+            .collect(),
+        _ => {
+            return Err(anyhow!(
+                "Unsupported WAV format {} with {} bits per sample",
+                format_tag,
+                bits_per_sample
+            ))
+        }
+    };
+
+    Ok(WavData {
+        channels,
+        sample_rate,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write(format: WavFormat, channels: u16, samples: &[f32]) -> Vec<u8> {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), format, channels, 44_100).unwrap();
+        writer.write_samples(samples).unwrap();
+        writer.finalize().unwrap().into_inner()
+    }
+
+    #[test]
+    fn float_round_trip() {
+        let samples = [0.0, 0.5, -0.25, 1.5, -1.0, 0.125];
+        let bytes = write(WavFormat::Float32, 2, &samples);
+        assert_eq!(bytes.len(), HEADER_LEN as usize + samples.len() * 4);
+        assert_eq!(&bytes[4..8], &(bytes.len() as u32 - 8).to_le_bytes());
+        assert_eq!(&bytes[40..44], &(samples.len() as u32 * 4).to_le_bytes());
+
+        let wav = read_wav(bytes.as_slice()).unwrap();
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.sample_rate, 44_100);
+        // Floats are not clipped.
+        assert_eq!(wav.samples, samples);
+    }
+
+    #[test]
+    fn pcm_round_trip_clips() {
+        let bytes = write(WavFormat::Pcm16, 1, &[0.0, 0.5, -2.0, 2.0]);
+        assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+
+        let wav = read_wav(bytes.as_slice()).unwrap();
+        assert_eq!(wav.channels, 1);
+        let expected = [0.0, 0.5, -1.0, 1.0];
+        for (sample, expected) in wav.samples.iter().zip(expected) {
+            assert!(
+                (sample - expected).abs() < 1.0 / i16::MAX as f32,
+                "{} != {}",
+                sample,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_chunks_are_skipped() {
+        let bytes = write(WavFormat::Float32, 1, &[0.25]);
+        // Insert a LIST chunk of odd length, which is padded, between the fmt and the data chunk.
+        let mut with_list = bytes[..36].to_vec();
+        with_list.extend_from_slice(b"LIST");
+        with_list.extend_from_slice(&3u32.to_le_bytes());
+        with_list.extend_from_slice(&[1, 2, 3, 0]);
+        with_list.extend_from_slice(&bytes[36..]);
+
+        let wav = read_wav(with_list.as_slice()).unwrap();
+        assert_eq!(wav.samples, [0.25]);
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        assert!(read_wav(&b"RIFF\0\0\0\0WAVX"[..]).is_err());
+        let bytes = write(WavFormat::Float32, 1, &[0.25, 0.5]);
+        assert!(read_wav(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_wav(&bytes[..36]).is_err());
+    }
+
+    #[test]
+    fn into_mono_averages_the_channels() {
+        let wav = WavData {
+            channels: 2,
+            sample_rate: 44_100,
+            samples: vec![1.0, 0.0, 0.5, 0.5],
+        };
+        assert_eq!(wav.into_mono(), [0.5, 0.5]);
+    }
+
+    #[test]
+    fn stops_at_the_size_limit() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), WavFormat::Float32, 1, 44_100).unwrap();
+        writer.data_len = MAX_DATA_LEN - 4;
+        writer.write_samples(&[0.5]).unwrap();
+        assert!(writer.write_samples(&[0.5]).is_err());
+        assert_eq!(writer.data_len, MAX_DATA_LEN);
+
+        // The RIFF size is the largest a u32 can hold.
+        let bytes = writer.finalize().unwrap().into_inner();
+        assert_eq!(&bytes[4..8], &u32::MAX.to_le_bytes());
+        assert_eq!(bytes.len(), HEADER_LEN as usize + 4);
+    }
+}